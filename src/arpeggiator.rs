@@ -0,0 +1,227 @@
+use crate::random;
+use crate::sequencer::SequencerNote;
+use wmidi::{Channel, Note, U7};
+
+/// Above ten octaves every note is out of the MIDI range
+const MAX_OCTAVES: u8 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArpMode {
+    Up,
+    Down,
+    /// Up and back down, without repeating the highest and lowest notes
+    UpDown,
+    Random,
+    /// In the order the notes were played
+    AsPlayed,
+}
+
+/// Arpeggiates the notes held on the MIDI input. Steps are locked to the beat grid of the
+/// timeline, so the arpeggio stays in time with the Link session.
+#[derive(Clone, Debug)]
+pub struct Arpeggiator {
+    channel: Channel,
+    mode: ArpMode,
+    /// Number of octaves the pattern spans
+    octaves: u8,
+    /// Time between two steps in ticks
+    rate: i64,
+    /// Note length in ticks
+    gate: i64,
+    /// Keep arpeggiating after the keys are released, until a new chord is played
+    latch: bool,
+    seed: u64,
+    /// Notes being arpeggiated, in the order they were played
+    held: Vec<(Note, U7)>,
+    /// Keys that are physically down
+    pressed: Vec<Note>,
+}
+
+impl Arpeggiator {
+    pub fn new(channel: Channel, mode: ArpMode, rate: i64) -> Self {
+        let rate = rate.max(1);
+        Self {
+            channel,
+            mode,
+            octaves: 1,
+            rate,
+            gate: (rate / 2).max(1),
+            latch: false,
+            seed: 0,
+            held: Vec::new(),
+            pressed: Vec::new(),
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: ArpMode) {
+        self.mode = mode;
+    }
+
+    pub fn set_octaves(&mut self, octaves: u8) {
+        self.octaves = octaves.clamp(1, MAX_OCTAVES);
+    }
+
+    pub fn set_rate(&mut self, rate: i64, gate: i64) {
+        self.rate = rate.max(1);
+        self.gate = gate.max(1);
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    pub fn set_latch(&mut self, latch: bool) {
+        self.latch = latch;
+        if !latch {
+            let pressed = &self.pressed;
+            self.held.retain(|(note, _)| pressed.contains(note));
+        }
+    }
+
+    pub fn rate(&self) -> i64 {
+        self.rate
+    }
+
+    pub fn note_on(&mut self, note: Note, velocity: U7) {
+        // with latch on, the first key of a new chord replaces the latched one
+        if self.latch && self.pressed.is_empty() {
+            self.held.clear();
+        }
+        if !self.pressed.contains(&note) {
+            self.pressed.push(note);
+        }
+        if !self.held.iter().any(|(held, _)| *held == note) {
+            self.held.push((note, velocity));
+        }
+    }
+
+    pub fn note_off(&mut self, note: Note) {
+        self.pressed.retain(|pressed| *pressed != note);
+        if !self.latch {
+            self.held.retain(|(held, _)| *held != note);
+        }
+    }
+
+    fn pattern(&self) -> Vec<(Note, U7)> {
+        let mut notes = self.held.clone();
+        if self.mode != ArpMode::AsPlayed {
+            notes.sort_by_key(|(note, _)| u8::from(*note));
+        }
+        let mut pattern: Vec<(Note, U7)> = (0..i16::from(self.octaves))
+            .flat_map(|octave| {
+                notes.iter().filter_map(move |(note, velocity)| {
+                    let half_steps = i8::try_from(octave * 12).ok()?;
+                    Some((note.step(half_steps).ok()?, *velocity))
+                })
+            })
+            .collect();
+
+        match self.mode {
+            ArpMode::Down => pattern.reverse(),
+            ArpMode::UpDown if pattern.len() > 2 => {
+                let down: Vec<(Note, U7)> = pattern[1..pattern.len() - 1]
+                    .iter()
+                    .rev()
+                    .cloned()
+                    .collect();
+                pattern.extend(down);
+            }
+            _ => {}
+        }
+        pattern
+    }
+
+    /// The note played on step `step` of the timeline, i.e. at tick `step * rate`.
+    pub fn note_at(&self, step: i64) -> Option<SequencerNote> {
+        let pattern = self.pattern();
+        if pattern.is_empty() {
+            return None;
+        }
+        let index = match self.mode {
+            ArpMode::Random => (random::unit(self.seed, &[step]) * pattern.len() as f64) as usize,
+            _ => step.rem_euclid(pattern.len() as i64) as usize,
+        };
+        let (note, velocity) = pattern[index];
+        Some(SequencerNote::new(
+            self.channel,
+            note,
+            velocity,
+            step * self.rate,
+            self.gate,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arpeggiate(arpeggiator: &Arpeggiator, steps: i64) -> Vec<u8> {
+        (0..steps)
+            .filter_map(|step| arpeggiator.note_at(step))
+            .map(|note| u8::from(note.note()))
+            .collect()
+    }
+
+    fn chord(mode: ArpMode) -> Arpeggiator {
+        let mut arpeggiator = Arpeggiator::new(Channel::Ch1, mode, 24);
+        for note in [Note::E4, Note::C4, Note::G4] {
+            arpeggiator.note_on(note, U7::MAX);
+        }
+        arpeggiator
+    }
+
+    #[test]
+    fn modes() {
+        assert_eq!(arpeggiate(&chord(ArpMode::Up), 4), vec![60, 64, 67, 60]);
+        assert_eq!(arpeggiate(&chord(ArpMode::Down), 4), vec![67, 64, 60, 67]);
+        assert_eq!(
+            arpeggiate(&chord(ArpMode::UpDown), 5),
+            vec![60, 64, 67, 64, 60]
+        );
+        assert_eq!(arpeggiate(&chord(ArpMode::AsPlayed), 3), vec![64, 60, 67]);
+
+        let random = arpeggiate(&chord(ArpMode::Random), 32);
+        assert!(random.iter().all(|note| [60, 64, 67].contains(note)));
+        assert_eq!(random, arpeggiate(&chord(ArpMode::Random), 32));
+    }
+
+    #[test]
+    fn octave_range() {
+        let mut arpeggiator = chord(ArpMode::Up);
+        arpeggiator.set_octaves(2);
+        assert_eq!(arpeggiate(&arpeggiator, 6), vec![60, 64, 67, 72, 76, 79]);
+
+        // notes past the top of the MIDI range are left out
+        arpeggiator.set_octaves(u8::MAX);
+        let notes = arpeggiate(&arpeggiator, 19);
+        assert_eq!(notes[17..], [127, 60]);
+    }
+
+    #[test]
+    fn rate_is_positive() {
+        let arpeggiator = Arpeggiator::new(Channel::Ch1, ArpMode::Up, 0);
+        assert_eq!(arpeggiator.rate(), 1);
+    }
+
+    #[test]
+    fn latch() {
+        let mut arpeggiator = chord(ArpMode::Up);
+        arpeggiator.note_off(Note::E4);
+        assert_eq!(arpeggiate(&arpeggiator, 2), vec![60, 67]);
+
+        arpeggiator.set_latch(true);
+        for note in [Note::C4, Note::G4] {
+            arpeggiator.note_off(note);
+        }
+        assert_eq!(arpeggiate(&arpeggiator, 2), vec![60, 67]);
+
+        // a new chord replaces the latched notes
+        arpeggiator.note_on(Note::D4, U7::MAX);
+        assert_eq!(arpeggiate(&arpeggiator, 2), vec![62, 62]);
+
+        arpeggiator.note_off(Note::D4);
+        arpeggiator.set_latch(false);
+        assert!(arpeggiator.note_at(0).is_none());
+    }
+}
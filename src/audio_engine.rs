@@ -1,27 +1,250 @@
 use crate::audio_platform_cpal::AudioPlatformCpal;
-use crate::sequencer::{Sequencer, SequencerConfig, MidiEvent};
-use coremidi::{Client, Destination, PacketBuffer, OutputPort};
+use crate::euclid::Euclid;
+use crate::launch::LaunchQuantize;
+use crate::midi_clock::{ClockInput, ClockOutput};
+use crate::midi_input::{self, MidiInputEvent};
+use crate::mtc::{FrameRate, MtcInput, MtcOutput};
+use crate::scale::Scale;
+use crate::sequencer::{Sequencer, SequencerConfig, MidiEvent, PPQ};
+use crate::tap_tempo::TapTempo;
+use crate::timing::{InternalClock, TimingSource, DEFAULT_TEMPO};
+use coremidi::{Client, Destination, InputPort, PacketBuffer, PacketList, OutputPort, Source};
 use cpal::Stream;
+use wmidi::MidiMessage;
 use rusty_link::{AblLink, SessionState};
 use std::{
-    sync::{mpsc::Receiver, Arc, Mutex},
+    sync::{mpsc::{self, Receiver}, Arc, Mutex},
     time::Duration,
     mem::MaybeUninit
 };
-use wmidi::MidiMessage;
 use mach2::mach_time::{mach_absolute_time, mach_timebase_info};
 
-const NOTE_ON: u8 = 0x90;
-const NOTE_OFF: u8 = 0x80;
-
 pub enum UpdateSessionState {
     TempoPlus,
     TempoMinus,
     TogglePlaying,
+    /// Mute or unmute the track with this index
+    ToggleMute(usize),
+    ToggleSolo(usize),
+    /// Launch the clip in a slot of a track, as (track, slot), at the next launch boundary
+    LaunchClip(usize, usize),
+    /// Launch the clips in this row of every track at the next launch boundary
+    LaunchScene(usize),
+    /// Stop the track with this index at the next launch boundary
+    StopClip(usize),
+    /// The key that pitch quantizers snap to
+    SetKey(Scale),
+    /// Transpose all sequences by this many semitones
+    SetTranspose(i32),
+    /// Transpose all sequences by this many octaves, on top of the semitones
+    SetOctave(i32),
+    /// New parameters for the Euclidean sequence of the track with this index
+    SetEuclid(usize, Euclid),
+    /// Chase notes and controller values when playback starts or jumps
+    SetChase(bool),
+    /// Record into the track with this index, or stop recording with `None`
+    Arm(Option<usize>),
+    /// Send MIDI beat clock to the destination with this index, or stop sending it
+    ToggleClockOutput(usize),
+    /// Follow the MIDI clock on the input instead of the Link timeline, or go back to Link
+    ToggleClockInput,
+    /// Push the tempo of the followed MIDI clock into the Link session
+    ToggleClockTempoToLink,
+    /// Send MIDI time code to the destination with this index, or stop sending it
+    ToggleTimecodeOutput(usize),
+    SetTimecodeRate(FrameRate),
+    /// Chase the MIDI time code on the input instead of the Link timeline, or go back to Link
+    ToggleTimecodeInput,
+    /// Time it takes a played note to reach the input port, e.g. the latency of the MIDI
+    /// interface. Recorded notes are moved back by this much.
+    SetInputLatency(Duration),
+    /// A tap of the tap tempo button at this host time in microseconds, e.g. from
+    /// `AblLink::clock_micros`
+    Tap(i64),
+    /// Speed up or slow down by this fraction, e.g. 0.05 for 5% faster, to line up by ear with
+    /// a source that isn't in the Link session. A nudge of zero ends the nudge, and takes it
+    /// back out of the current tempo.
+    Nudge(f64),
+}
+
+/// Number of intervals between taps that are averaged for tap tempo
+const TAP_TEMPO_SIZE: usize = 4;
+
+/// Where the beat position of the sequencer comes from
+#[derive(Clone, Copy, PartialEq)]
+enum Follow {
+    /// The Link session, or the internal clock when there is no session
+    Link,
+    MidiClock,
+    /// The transport time of the time code, at the tempo of the Link session
+    Timecode,
+}
+
+/// The Link session as a timing source. On macOS the Link clock is the host clock.
+struct LinkTiming {
+    link: &'static AblLink,
+    session_state: SessionState,
+    /// Play state of the session in the last buffer
+    playing: bool,
+}
+
+impl LinkTiming {
+    fn new(link: &'static AblLink) -> Self {
+        let mut session_state = SessionState::new();
+        link.capture_audio_session_state(&mut session_state);
+        let playing = session_state.is_playing();
+        Self {
+            link,
+            session_state,
+            playing,
+        }
+    }
+
+    /// Starts or stops the transport for all peers. Playback starts on the first beat of a bar,
+    /// stopping leaves the shared timeline where it is.
+    fn toggle_playing(&mut self, time: i64, quantum: f64) {
+        match self.session_state.is_playing() {
+            true => self.session_state.set_is_playing(false, time as u64),
+            false => self
+                .session_state
+                .set_is_playing_and_request_beat_at_time(true, time as u64, 0., quantum),
+        }
+        self.link.commit_audio_session_state(&self.session_state);
+    }
+
+    /// The new play state, if the transport was started or stopped since the last call, by us
+    /// or by another peer.
+    fn transport(&mut self) -> Option<bool> {
+        let playing = self.session_state.is_playing();
+        if playing == self.playing {
+            return None;
+        }
+        self.playing = playing;
+        Some(playing)
+    }
+
+    /// Changes the tempo of the session for all peers.
+    fn set_tempo(&mut self, tempo: f64, time: i64) {
+        self.session_state.set_tempo(tempo, time);
+        self.link.commit_audio_session_state(&self.session_state);
+    }
+}
+
+impl TimingSource for LinkTiming {
+    fn capture(&mut self) {
+        self.link.capture_audio_session_state(&mut self.session_state);
+    }
+
+    fn tempo(&self) -> f64 {
+        self.session_state.tempo()
+    }
+
+    fn beat_at_time(&self, time: i64, quantum: f64) -> f64 {
+        self.session_state.beat_at_time(time, quantum)
+    }
+
+    fn phase_at_time(&self, time: i64, quantum: f64) -> f64 {
+        self.session_state.phase_at_time(time, quantum)
+    }
+
+    fn is_playing(&self, _time: i64) -> bool {
+        self.session_state.is_playing()
+    }
+}
+
+/// The timing sources the engine can follow.
+struct Timing {
+    link: Option<LinkTiming>,
+    internal: InternalClock,
+    clock: ClockInput,
+    timecode: MtcInput,
+    follow: Follow,
+    /// Push the tempo of the followed MIDI clock into the Link session
+    clock_tempo_to_link: bool,
+    /// The amount of the active nudge, while nudging
+    nudged: Option<f64>,
+}
+
+impl Timing {
+    fn source(&self) -> &dyn TimingSource {
+        match (self.follow, &self.link) {
+            (Follow::Link, Some(link)) => link,
+            (Follow::Link, None) => &self.internal,
+            (Follow::MidiClock, _) => &self.clock,
+            (Follow::Timecode, _) => &self.timecode,
+        }
+    }
+
+    /// Follow `follow`, or go back to Link if it's followed already.
+    fn toggle_follow(&mut self, follow: Follow) {
+        self.follow = match self.follow == follow {
+            true => Follow::Link,
+            false => follow,
+        };
+    }
+
+    /// Picks up changes of the Link session, and passes tempo between the sources.
+    fn capture(&mut self, time: i64) {
+        if let Some(link) = &mut self.link {
+            link.capture();
+            if let (Follow::MidiClock, true, Some(tempo)) =
+                (self.follow, self.clock_tempo_to_link, self.clock.measured_tempo())
+            {
+                if (tempo - link.tempo()).abs() > 0.01 {
+                    link.set_tempo(tempo, time);
+                }
+            }
+        }
+        self.timecode.set_tempo(self.session_tempo());
+    }
+
+    /// Tempo of the Link session, or of the internal clock without one.
+    fn session_tempo(&self) -> f64 {
+        match &self.link {
+            Some(link) => link.tempo(),
+            None => self.internal.tempo(),
+        }
+    }
+
+    /// Changes the tempo of the Link session, or of the internal clock without one.
+    fn set_session_tempo(&mut self, tempo: f64, time: i64) {
+        match &mut self.link {
+            Some(link) => link.set_tempo(tempo, time),
+            None => self.internal.set_tempo(tempo, time),
+        }
+    }
+
+    /// Nudges the session tempo by `amount`, or ends the nudge with an amount of zero.
+    fn nudge(&mut self, amount: f64, time: i64) {
+        // the active nudge is taken out of the current tempo, so that tempo changes made by
+        // peers or taps while nudging are kept
+        let tempo = self.session_tempo() / (1. + self.nudged.unwrap_or(0.));
+        self.nudged = match amount == 0. {
+            true => None,
+            false => Some(amount),
+        };
+        self.set_session_tempo(tempo * (1. + amount), time);
+    }
+
+    /// Handles MIDI clock and time code from the input. Returns whether the message was one.
+    fn receive(&mut self, message: &MidiMessage, time: i64) -> bool {
+        self.clock.receive(message, time) || self.timecode.receive(message, time)
+    }
+
+    /// Whether the sequencer plays. The Link timeline always runs, the internal clock, external
+    /// clocks and time code stand still while they're stopped or drop out, so the same buffer
+    /// isn't rendered over and over. Sounding notes are released when this turns false.
+    fn runs(&self, time: i64) -> bool {
+        (self.follow == Follow::Link && self.link.is_some()) || self.source().is_playing(time)
+    }
 }
 
+/// Input latency until one is set with `UpdateSessionState::SetInputLatency`
+const DEFAULT_INPUT_LATENCY: Duration = Duration::from_millis(1);
+
 pub struct AudioEngine {
     pub stream: Stream,
+    pub input_port: InputPort,
 }
 
 // Using CoreMIDI objects with `lazy_static` in this example, because the `cpal` audio callback requires all variables
@@ -34,19 +257,67 @@ lazy_static! {
 
 impl AudioEngine {
     pub fn new(
-        link: &'static AblLink,
+        link: Option<&'static AblLink>,
         audio_cpal: AudioPlatformCpal,
         input: Receiver<UpdateSessionState>,
         quantum: Arc<Mutex<f64>>,
     ) -> Self {
         //let prev_beat: i32 = -1;
         //let host_time_filter = HostTimeFilter::new();
-        let mut audio_session_state = SessionState::new();
-        link.capture_audio_session_state(&mut audio_session_state);
+        // the sample rate and buffer size are updated from the audio callback
+        let config = SequencerConfig::new(120., 44100, 512.0, PPQ);
+        let mut sequencer = Sequencer::new(config);
+
+        // forward incoming MIDI from the first source to the audio callback
+        let (midi_input_tx, midi_input_rx) = mpsc::channel::<MidiInputEvent>();
+        let input_port = CLIENT
+            .input_port("sequencer-rs-midiin", move |packets: &PacketList| {
+                for packet in packets.iter() {
+                    for message in midi_input::messages(packet.data()) {
+                        // a timestamp of zero means the message was received right now
+                        let timestamp = match packet.timestamp() {
+                            0 => unsafe { mach_absolute_time() },
+                            timestamp => timestamp,
+                        };
+                        let event = MidiInputEvent { timestamp, message };
+                        midi_input_tx.send(event).ok();
+                    }
+                }
+            })
+            .unwrap();
+        if let Some(source) = Source::from_index(0) {
+            input_port.connect_source(&source).unwrap();
+        }
 
-        // TODO: get actual buffer size and sample time from cpal, and sync tempo with Link
-        let config = SequencerConfig::new(120., 44100, 512.0);
-        let sequencer = Sequencer::new(config);
+        let mut current_quantum = 4.;
+        let mut clock_output = ClockOutput::new();
+        // indices of the destinations that receive MIDI beat clock
+        let mut clock_destinations: Vec<usize> = Vec::new();
+        let mut timecode_output = MtcOutput::new(FrameRate::Fps25);
+        let mut timecode_destinations: Vec<usize> = Vec::new();
+        // without a Link session, the internal clock runs the timeline
+        let mut timing = Timing {
+            link: link.map(LinkTiming::new),
+            internal: InternalClock::new(DEFAULT_TEMPO),
+            clock: ClockInput::new(),
+            timecode: MtcInput::new(),
+            follow: Follow::Link,
+            clock_tempo_to_link: false,
+            nudged: None,
+        };
+        let mut tap_tempo = TapTempo::new(TAP_TEMPO_SIZE);
+        let mut input_latency = DEFAULT_INPUT_LATENCY;
+        // whether the last buffer was rendered, notes are released when the timing source stops
+        let mut running = false;
+
+        // the tracks are built playing, they stop right away if the session is stopped when the
+        // engine starts
+        if let Some(link) = &timing.link {
+            if !link.playing {
+                let time = host_micros(unsafe { mach_absolute_time() }, &timebase_info());
+                sequencer.stop(link.beat_at_time(time, current_quantum), LaunchQuantize::Immediate);
+            }
+        }
 
         // define audio callback
         let callback = move |buffer_size: usize,
@@ -55,56 +326,181 @@ impl AudioEngine {
                              sample_time: Duration,
                              sample_clock: u64| {
 
+            // TODO: make sure we don't exceed capacity
+            let info = timebase_info();
+            // the buffer is heard after the output latency, so the timeline is followed at that
+            // time, and the MIDI rendered with it is scheduled from there as well
+            let output_latency_ticks = output_latency.as_nanos() as f64 * info.denom as f64 / info.numer as f64;
+            let output_time = unsafe { mach_absolute_time() } + output_latency_ticks as u64;
+            let time = host_micros(output_time, &info);
+            // offsets are converted to host time in full precision, not in whole milliseconds
+            let host_ticks_per_sample = 1.0e9 / sample_rate as f64 * info.denom as f64 / info.numer as f64;
+
+            // launches are quantized to the quantum shared with the Link session, the last known
+            // value is used while another thread holds the lock
+            if let Ok(value) = quantum.try_lock() {
+                current_quantum = *value;
+            }
+            sequencer.set_quantum(current_quantum);
+            sequencer.set_audio_config(sample_rate, buffer_size as f64);
+            timing.capture(time);
+
             let mut buffer: Vec<f32> = Vec::with_capacity(buffer_size);
 
             // fill up buffer with silence
             for _ in 0..buffer_size {
-                if !audio_session_state.is_playing() {
+                if !timing.source().is_playing(time) {
                     buffer.push(0.);
                     continue;
                 }
             }
 
-            let beat_position = audio_session_state.beat_at_time(link.clock_micros(), 4.);
-            // TODO: make sure we don't exceed capacity
-            let now = unsafe { mach_absolute_time() };
-            let info = timebase_info();
-            let ms_to_host_ticks: u64 = (1.0 / ((info.numer as f64 / info.denom as f64) * 1.0e-6)) as u64;
+            for update in input.try_iter() {
+                match update {
+                    UpdateSessionState::ToggleMute(index) => {
+                        if let Some(track) = sequencer.track_mut(index) {
+                            track.set_muted(!track.is_muted());
+                        }
+                    }
+                    UpdateSessionState::ToggleSolo(index) => {
+                        if let Some(track) = sequencer.track_mut(index) {
+                            track.set_solo(!track.is_solo());
+                        }
+                    }
+                    UpdateSessionState::LaunchClip(index, slot) => {
+                        let beat = timing.source().beat_at_time(time, current_quantum);
+                        sequencer.launch_clip(index, slot, beat);
+                    }
+                    UpdateSessionState::LaunchScene(scene) => {
+                        let beat = timing.source().beat_at_time(time, current_quantum);
+                        sequencer.launch_scene(scene, beat);
+                    }
+                    UpdateSessionState::StopClip(index) => {
+                        let beat = timing.source().beat_at_time(time, current_quantum);
+                        sequencer.stop_clip(index, beat);
+                    }
+                    UpdateSessionState::SetKey(key) => sequencer.set_key(key),
+                    UpdateSessionState::SetTranspose(semitones) => sequencer.set_transpose(semitones),
+                    UpdateSessionState::SetOctave(octaves) => sequencer.set_octave(octaves),
+                    UpdateSessionState::SetEuclid(index, euclid) => {
+                        let beat = timing.source().beat_at_time(time, current_quantum);
+                        sequencer.set_euclid(index, &euclid, beat);
+                    }
+                    UpdateSessionState::SetChase(chase) => sequencer.set_chase(chase),
+                    UpdateSessionState::Arm(index) => sequencer.arm(index),
+                    UpdateSessionState::ToggleClockOutput(index) => {
+                        toggle(&mut clock_destinations, index)
+                    }
+                    UpdateSessionState::ToggleClockInput => timing.toggle_follow(Follow::MidiClock),
+                    UpdateSessionState::ToggleClockTempoToLink => {
+                        timing.clock_tempo_to_link = !timing.clock_tempo_to_link
+                    }
+                    UpdateSessionState::ToggleTimecodeOutput(index) => {
+                        toggle(&mut timecode_destinations, index)
+                    }
+                    UpdateSessionState::SetTimecodeRate(rate) => timecode_output.set_rate(rate),
+                    UpdateSessionState::ToggleTimecodeInput => timing.toggle_follow(Follow::Timecode),
+                    UpdateSessionState::TogglePlaying => match &mut timing.link {
+                        Some(link) => link.toggle_playing(time, current_quantum),
+                        None => {
+                            let playing = timing.internal.is_playing(time);
+                            timing.internal.set_playing(!playing, time);
+                        }
+                    },
+                    UpdateSessionState::Tap(tapped) => {
+                        if let Some(tempo) = tap_tempo.tap(tapped) {
+                            // an active nudge stays on top of the tapped tempo
+                            let nudged = tempo * (1. + timing.nudged.unwrap_or(0.));
+                            timing.set_session_tempo(nudged, time);
+                        }
+                    }
+                    UpdateSessionState::Nudge(amount) => timing.nudge(amount, time),
+                    UpdateSessionState::SetInputLatency(latency) => input_latency = latency,
+                    // stepwise tempo changes are not handled in the audio callback yet
+                    _ => {}
+                }
+            }
+
+            // the sequences follow the transport of the Link session, and start and stop on the
+            // quantum boundary
+            let transport = timing.link.as_mut().and_then(|link| link.transport());
+            if let (Some(playing), Follow::Link) = (transport, timing.follow) {
+                let beat = timing.source().beat_at_time(time, current_quantum);
+                match playing {
+                    true => sequencer.start(beat, LaunchQuantize::Bar),
+                    false => sequencer.stop(beat, LaunchQuantize::Bar),
+                }
+            }
+
+            for event in midi_input_rx.try_iter() {
+                // position on the timeline at which the message was played
+                let played = host_micros(event.timestamp, &info) - input_latency.as_micros() as i64;
+                if timing.receive(&event.message, played) {
+                    continue;
+                }
+                let played_at = timing.source().beat_at_time(played, current_quantum);
+                sequencer.midi_input(&event.message, played_at);
+            }
+
+            let source = timing.source();
+            let beat_position = source.beat_at_time(time, current_quantum);
+            let tempo = source.tempo();
+            let playing = source.is_playing(time);
 
             let mut midi: Vec<MidiEvent> = Vec::with_capacity(10);
-            sequencer.render_timeline(now, beat_position, &mut midi);
+            let runs = timing.runs(time);
+            if runs {
+                sequencer.render_source(source, time, &mut midi);
+            } else if running {
+                // nothing renders the note-offs of a stopped source, they'd hang until it starts
+                sequencer.release_all(&mut midi);
+            }
+            running = runs;
 
-            let output_latency_in_ms = output_latency.as_millis();
-            //let output_latency_host_ticks = output_latency_in_ms as u64 * ms_to_host_ticks;
+            let mut clock: Vec<MidiEvent> = Vec::with_capacity(10);
+            let samples_per_beat = sample_rate as f64 * 60. / tempo;
+            clock_output.render(
+                beat_position,
+                playing,
+                buffer_size as f64,
+                samples_per_beat,
+                &mut clock,
+            );
+            let mut timecode: Vec<MidiEvent> = Vec::with_capacity(10);
+            timecode_output.render(
+                beat_position,
+                tempo,
+                playing,
+                buffer_size as f64,
+                sample_rate as f64,
+                &mut timecode,
+            );
 
+            let send = |event: &MidiEvent, destination: &Destination| {
+                let timestamp = output_time + (event.offset() * host_ticks_per_sample) as u64;
+
+                // notes, CCs and everything else go out in the order they were rendered in
+                let message = event.message();
+                let mut data = vec![0u8; message.bytes_size()];
+                match message.copy_to_slice(&mut data) {
+                    Ok(size) => {
+                        let p = PacketBuffer::new(timestamp, &data[..size]);
+                        OUTPUT_PORT.send(destination, &p).unwrap();
+                    }
+                    Err(_) => println!("unknown item"),
+                }
+            };
             for event in midi.iter() {
-                let offset_in_ms = event.offset() / 44.1;
-                let offset_in_host_ticks = offset_in_ms as u64 * ms_to_host_ticks;
-                //println!("ms to host ticks              {}", ms_to_host_ticks);
-                //println!("offset in samples             {}", event.offset());
-                //println!("offset in ms                  {}", offset_in_ms);
-                //println!("offset in host ticks          {}", offset_in_host_ticks);
-                // TODO: account for output latency
-                //let timestamp = now + offset_in_host_ticks as u64 - output_latency_host_ticks as u64;
-                //println!("output latency in host ticks  {}", output_latency_host_ticks);
-                let timestamp = now + offset_in_host_ticks as u64;
-
-                match event.message() {
-                    MidiMessage::NoteOn(_, note, velocity) => {
-                        let data = [NOTE_ON, u8::from(*note), u8::from(*velocity)];
-                        let p = PacketBuffer::new(timestamp as u64, &data);
-                        OUTPUT_PORT.send(&DESTINATION, &p).unwrap();                   
-                        //println!("note on at time   {}", timestamp);
-                    },
-                    MidiMessage::NoteOff(_, note, _) => {
-                        let data = [NOTE_OFF, u8::from(*note), 0];
-                        let p = PacketBuffer::new(timestamp as u64, &data);
-                        OUTPUT_PORT.send(&DESTINATION, &p).unwrap();                   
-                        //println!("note off at time  {}", timestamp);
-                    },
-                    _ => println!("unknown item"),
+                send(event, &DESTINATION);
+            }
+            for (events, destinations) in [(&clock, &clock_destinations), (&timecode, &timecode_destinations)] {
+                for index in destinations {
+                    if let Some(destination) = Destination::from_index(*index) {
+                        for event in events.iter() {
+                            send(event, &destination);
+                        }
+                    }
                 }
-                //println!("---------------------------");
             }
 
             buffer
@@ -113,7 +509,22 @@ impl AudioEngine {
         // Build audio stream and start playback
         let stream = audio_cpal.build_stream::<f32>(callback);
 
-        Self { stream }
+        Self { stream, input_port }
+    }
+}
+
+/// Converts host time from mach ticks to microseconds.
+fn host_micros(ticks: u64, info: &mach_timebase_info) -> i64 {
+    (ticks as f64 * info.numer as f64 / info.denom as f64 / 1000.) as i64
+}
+
+/// Adds `index` to the destinations, or removes it if it's there.
+fn toggle(destinations: &mut Vec<usize>, index: usize) {
+    match destinations.iter().position(|i| *i == index) {
+        Some(position) => {
+            destinations.remove(position);
+        }
+        None => destinations.push(index),
     }
 }
 
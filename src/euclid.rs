@@ -0,0 +1,113 @@
+use crate::step::{Step, StepGrid};
+use wmidi::{Note, U7};
+
+/// Distributes `pulses` onsets as evenly as possible over `steps` steps, using Bjorklund's
+/// algorithm.
+pub fn bjorklund(pulses: usize, steps: usize) -> Vec<bool> {
+    let pulses = pulses.min(steps);
+    if pulses == 0 {
+        return vec![false; steps];
+    }
+
+    let mut heads: Vec<Vec<bool>> = vec![vec![true]; pulses];
+    let mut remainders: Vec<Vec<bool>> = vec![vec![false]; steps - pulses];
+    while remainders.len() > 1 {
+        let count = heads.len().min(remainders.len());
+        let paired = heads
+            .iter()
+            .zip(remainders.iter())
+            .map(|(head, remainder)| [head.as_slice(), remainder.as_slice()].concat())
+            .collect();
+        remainders = if heads.len() > count {
+            heads.split_off(count)
+        } else {
+            remainders.split_off(count)
+        };
+        heads = paired;
+    }
+
+    heads.into_iter().chain(remainders).flatten().collect()
+}
+
+/// An E(k, n) rhythm: `pulses` notes spread over `steps` steps, shifted later by `rotation`
+/// steps.
+#[derive(Clone, Debug)]
+pub struct Euclid {
+    pub pulses: usize,
+    pub steps: usize,
+    pub rotation: usize,
+    pub note: Note,
+    pub velocity: U7,
+    /// Gate length in ticks
+    pub gate: i64,
+}
+
+impl Euclid {
+    pub fn new(pulses: usize, steps: usize, note: Note, velocity: U7, gate: i64) -> Self {
+        Self {
+            pulses,
+            steps,
+            rotation: 0,
+            note,
+            velocity,
+            gate,
+        }
+    }
+
+    pub fn pattern(&self) -> Vec<bool> {
+        let mut pattern = bjorklund(self.pulses, self.steps);
+        if !pattern.is_empty() {
+            let rotation = self.rotation % pattern.len();
+            pattern.rotate_right(rotation);
+        }
+        pattern
+    }
+
+    /// Replaces the trigs of a step grid with the rhythm. The grid must have `steps` steps.
+    pub fn fill(&self, grid: &mut StepGrid) {
+        for (index, onset) in self.pattern().into_iter().enumerate() {
+            if onset {
+                grid.set_step(index, Step::new(self.note, self.velocity, self.gate));
+            } else {
+                grid.clear_step(index);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(pulses: usize, steps: usize) -> String {
+        bjorklund(pulses, steps)
+            .into_iter()
+            .map(|onset| if onset { 'x' } else { '.' })
+            .collect()
+    }
+
+    #[test]
+    fn bjorklund_distributions() {
+        assert_eq!(pattern(3, 8), "x..x..x.");
+        assert_eq!(pattern(5, 8), "x.xx.xx.");
+        assert_eq!(pattern(4, 16), "x...x...x...x...");
+        assert_eq!(pattern(0, 4), "....");
+        assert_eq!(pattern(4, 4), "xxxx");
+        assert_eq!(pattern(9, 4), "xxxx");
+        assert_eq!(pattern(3, 0), "");
+    }
+
+    #[test]
+    fn rotation() {
+        let mut euclid = Euclid::new(3, 8, Note::C2, U7::MAX, 6);
+        euclid.rotation = 2;
+        let rotated: Vec<usize> = euclid
+            .pattern()
+            .iter()
+            .enumerate()
+            .filter(|(_, onset)| **onset)
+            .map(|(index, _)| index)
+            .collect();
+        assert_eq!(rotated, vec![0, 2, 5]);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::random;
+
+/// What a track does when a clip has played a number of times.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FollowAction {
+    /// The next clip on the track, wrapping around to the first one
+    Next,
+    Previous,
+    First,
+    /// Any clip on the track, including the one that played
+    Random,
+    /// Any clip on the track except the one that played
+    Other,
+    Stop,
+}
+
+/// Follow actions of a clip, chosen by weight. The choice only depends on the seed and the
+/// position on the timeline, so offline renders come out the same every time.
+#[derive(Clone, Debug)]
+pub struct FollowActions {
+    /// Number of times the clip plays before an action is taken
+    after: u32,
+    actions: Vec<(FollowAction, u32)>,
+    seed: u64,
+}
+
+impl FollowActions {
+    pub fn new(after: u32, actions: Vec<(FollowAction, u32)>) -> Self {
+        Self {
+            after: after.max(1),
+            actions,
+            seed: 0,
+        }
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    pub fn after(&self) -> u32 {
+        self.after
+    }
+
+    /// The action taken at timeline position `tick`, or `None` if all weights are zero.
+    pub fn choose(&self, tick: i64) -> Option<FollowAction> {
+        let total: u32 = self.actions.iter().map(|(_, weight)| weight).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut choice = (random::unit(self.seed, &[tick, 0]) * total as f64) as u32;
+        for (action, weight) in &self.actions {
+            if choice < *weight {
+                return Some(*action);
+            }
+            choice -= weight;
+        }
+        None
+    }
+
+    /// The slot that `action` plays after `slot`, given which slots of the track hold a clip.
+    /// `None` stops the track.
+    pub fn target(
+        &self,
+        action: FollowAction,
+        slot: usize,
+        occupied: &[bool],
+        tick: i64,
+    ) -> Option<usize> {
+        let clips: Vec<usize> = (0..occupied.len()).filter(|i| occupied[*i]).collect();
+        let position = clips.iter().position(|clip| *clip == slot);
+        let pick = |clips: &[usize]| {
+            let index = random::unit(self.seed, &[tick, 1]) * clips.len() as f64;
+            clips.get(index as usize).copied()
+        };
+        match action {
+            FollowAction::Next => {
+                let next = position.map_or(0, |position| (position + 1) % clips.len());
+                clips.get(next).copied()
+            }
+            FollowAction::Previous => {
+                let previous =
+                    position.map_or(0, |position| (position + clips.len() - 1) % clips.len());
+                clips.get(previous).copied()
+            }
+            FollowAction::First => clips.first().copied(),
+            FollowAction::Random => pick(&clips),
+            FollowAction::Other => {
+                let others: Vec<usize> = clips.iter().copied().filter(|c| *c != slot).collect();
+                pick(&others).or(Some(slot))
+            }
+            FollowAction::Stop => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weighted_choice() {
+        let follow = FollowActions::new(
+            1,
+            vec![
+                (FollowAction::Next, 3),
+                (FollowAction::Stop, 1),
+                (FollowAction::First, 0),
+            ],
+        );
+        let choices: Vec<FollowAction> = (0..1000).filter_map(|tick| follow.choose(tick)).collect();
+        let next = choices
+            .iter()
+            .filter(|action| **action == FollowAction::Next)
+            .count();
+        assert!((700..800).contains(&next), "{}", next);
+        assert!(!choices.contains(&FollowAction::First));
+
+        let choices_again: Vec<FollowAction> =
+            (0..1000).filter_map(|tick| follow.choose(tick)).collect();
+        assert_eq!(choices, choices_again);
+        assert_eq!(FollowActions::new(1, vec![]).choose(0), None);
+    }
+
+    #[test]
+    fn targets() {
+        let follow = FollowActions::new(2, vec![]);
+        let occupied = [true, false, true, true];
+        assert_eq!(follow.target(FollowAction::Next, 0, &occupied, 0), Some(2));
+        assert_eq!(follow.target(FollowAction::Next, 3, &occupied, 0), Some(0));
+        assert_eq!(
+            follow.target(FollowAction::Previous, 0, &occupied, 0),
+            Some(3)
+        );
+        assert_eq!(follow.target(FollowAction::First, 3, &occupied, 0), Some(0));
+        assert_eq!(follow.target(FollowAction::Stop, 3, &occupied, 0), None);
+        for tick in 0..100 {
+            let other = follow.target(FollowAction::Other, 2, &occupied, tick);
+            assert!(other == Some(0) || other == Some(3));
+            let random = follow.target(FollowAction::Random, 2, &occupied, tick);
+            assert!(random.is_some_and(|slot| occupied[slot]));
+        }
+        assert_eq!(follow.target(FollowAction::Other, 0, &[true], 0), Some(0));
+    }
+}
@@ -0,0 +1,189 @@
+use crate::sequencer::SequencerNote;
+use crate::smf::{self, SmfError};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SwingGrid {
+    Eighth,
+    Sixteenth,
+}
+
+/// Delays every other step of an 8th or 16th grid.
+#[derive(Clone, Debug)]
+pub struct Swing {
+    /// Position of the off-beat within a pair of steps, in percent. 50% is straight, 66% is a
+    /// triplet feel.
+    amount: f64,
+    grid: SwingGrid,
+}
+
+impl Swing {
+    pub fn new(amount: f64, grid: SwingGrid) -> Self {
+        Self {
+            amount: amount.clamp(50., 75.),
+            grid,
+        }
+    }
+
+    /// Timing offset in ticks for an event at `tick`. The time between two on-beats is warped
+    /// so that the off-beat lands at `amount`, and events in between move along with it.
+    pub fn offset(&self, tick: i64, ppq: i64) -> f64 {
+        let step = match self.grid {
+            SwingGrid::Eighth => ppq as f64 / 2.,
+            SwingGrid::Sixteenth => ppq as f64 / 4.,
+        };
+        let pair = step * 2.;
+        let position = (tick as f64).rem_euclid(pair);
+        let off_beat = pair * self.amount / 100.;
+
+        let swung = if position < step {
+            position * off_beat / step
+        } else {
+            off_beat + (position - step) * (pair - off_beat) / step
+        };
+        swung - position
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GrooveStep {
+    /// Timing offset in ticks
+    pub timing: f64,
+    /// Velocity multiplier
+    pub velocity: f64,
+}
+
+impl Default for GrooveStep {
+    fn default() -> Self {
+        Self {
+            timing: 0.,
+            velocity: 1.,
+        }
+    }
+}
+
+/// Timing and velocity offsets for each position of a grid, applied to events at render time.
+#[derive(Clone, Debug)]
+pub struct Groove {
+    /// Grid resolution in ticks
+    resolution: i64,
+    steps: Vec<GrooveStep>,
+}
+
+impl Groove {
+    pub fn new(resolution: i64, steps: Vec<GrooveStep>) -> Self {
+        Self { resolution, steps }
+    }
+
+    /// Lifts the feel of a recorded take: for every position of a grid of `step_count` steps,
+    /// the average distance of the notes to the grid and their average velocity relative to the
+    /// whole take. A grid without steps gives an empty groove.
+    pub fn extract(notes: &[SequencerNote], resolution: i64, step_count: usize) -> Self {
+        if step_count == 0 {
+            return Self::new(resolution, Vec::new());
+        }
+        let mut timing = vec![Vec::new(); step_count];
+        let mut velocity = vec![Vec::new(); step_count];
+        for note in notes {
+            let grid_position = Self::grid_position(note.tick(), resolution);
+            let index = grid_position.rem_euclid(step_count as i64) as usize;
+            timing[index].push((note.tick() - grid_position * resolution) as f64);
+            velocity[index].push(u8::from(note.velocity()) as f64);
+        }
+
+        let mean = |values: &[f64]| values.iter().sum::<f64>() / values.len() as f64;
+        let all_velocities: Vec<f64> = velocity.iter().flatten().copied().collect();
+        let mean_velocity = mean(&all_velocities);
+
+        let steps = (0..step_count)
+            .map(|index| {
+                if timing[index].is_empty() {
+                    return GrooveStep::default();
+                }
+                GrooveStep {
+                    timing: mean(&timing[index]),
+                    velocity: mean(&velocity[index]) / mean_velocity,
+                }
+            })
+            .collect();
+
+        Self::new(resolution, steps)
+    }
+
+    /// Extracts a groove from all notes in a Standard MIDI File.
+    pub fn from_midi_file(
+        bytes: &[u8],
+        ppq: i64,
+        resolution: i64,
+        step_count: usize,
+    ) -> Result<Self, SmfError> {
+        let notes = smf::read_notes(bytes, ppq)?;
+        Ok(Self::extract(&notes, resolution, step_count))
+    }
+
+    /// The groove step for the grid position closest to `tick`.
+    pub fn step(&self, tick: i64) -> Option<&GrooveStep> {
+        if self.steps.is_empty() {
+            return None;
+        }
+        let grid_position = Self::grid_position(tick, self.resolution);
+        let index = grid_position.rem_euclid(self.steps.len() as i64) as usize;
+        self.steps.get(index)
+    }
+
+    fn grid_position(tick: i64, resolution: i64) -> i64 {
+        (tick as f64 / resolution as f64).round() as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wmidi::{Channel, Note, U7};
+
+    const PPQ: i64 = 96;
+
+    #[test]
+    fn swing_delays_off_beats() {
+        let swing = Swing::new(66., SwingGrid::Sixteenth);
+        assert_eq!(swing.offset(0, PPQ), 0.);
+        assert!((swing.offset(24, PPQ) - 7.68).abs() < 1e-9);
+        assert_eq!(swing.offset(48, PPQ), 0.);
+
+        let straight = Swing::new(50., SwingGrid::Eighth);
+        assert_eq!(straight.offset(48, PPQ), 0.);
+    }
+
+    #[test]
+    fn extract_groove() {
+        let note = |tick, velocity| {
+            SequencerNote::new(
+                Channel::Ch10,
+                Note::C2,
+                U7::from_u8_lossy(velocity),
+                tick,
+                12,
+            )
+        };
+        // a two step groove with a late, quiet off-beat
+        let notes = vec![note(0, 120), note(28, 60), note(48, 120), note(76, 60)];
+        let groove = Groove::extract(&notes, 24, 2);
+
+        assert_eq!(
+            groove.step(0),
+            Some(&GrooveStep {
+                timing: 0.,
+                velocity: 4. / 3.
+            })
+        );
+        assert_eq!(
+            groove.step(24),
+            Some(&GrooveStep {
+                timing: 4.,
+                velocity: 2. / 3.
+            })
+        );
+        assert_eq!(groove.step(96), groove.step(0));
+
+        assert_eq!(Groove::extract(&notes, 24, 0).step(0), None);
+    }
+}
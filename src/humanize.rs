@@ -0,0 +1,60 @@
+use crate::random;
+use wmidi::U7;
+
+/// Bounded random offsets for the timing and velocity of every note and step. Offsets are
+/// derived from the seed, the loop iteration and the position of the note in the sequence, so an
+/// offline render gives the same result every time.
+///
+/// Because a note's humanized position is fixed, it falls in exactly one buffer: notes that are
+/// moved out of the buffer they would originally be in are carried over to the next one (or were
+/// already sent with the previous one), and `MidiEvent::offset` never goes negative.
+///
+/// Raw `SequencerEvent`s are not humanized. A note-on and its note-off are two unrelated events
+/// there, and moving them independently could send the note-off first and leave the note hanging.
+#[derive(Clone, Debug)]
+pub struct Humanize {
+    /// Maximum timing offset in ticks, in both directions
+    timing: f64,
+    /// Maximum velocity offset, in both directions
+    velocity: u8,
+    seed: u64,
+}
+
+impl Humanize {
+    pub fn new(timing: f64, velocity: u8, seed: u64) -> Self {
+        Self {
+            timing: timing.abs(),
+            velocity,
+            seed,
+        }
+    }
+
+    pub fn timing_offset(&self, iteration: i64, index: usize) -> f64 {
+        random::bipolar(self.seed, &[iteration, index as i64, 0]) * self.timing
+    }
+
+    pub fn velocity(&self, iteration: i64, index: usize, velocity: U7) -> U7 {
+        let offset =
+            random::bipolar(self.seed, &[iteration, index as i64, 1]) * self.velocity as f64;
+        let velocity = (u8::from(velocity) as f64 + offset).round();
+        U7::from_u8_lossy(velocity.clamp(1., 127.) as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offsets_are_bounded() {
+        let humanize = Humanize::new(3., 10, 1234);
+        for iteration in 0..100 {
+            for index in 0..16 {
+                assert!(humanize.timing_offset(iteration, index).abs() <= 3.);
+                let velocity =
+                    u8::from(humanize.velocity(iteration, index, U7::from_u8_lossy(120)));
+                assert!((110..=127).contains(&velocity));
+            }
+        }
+    }
+}
@@ -0,0 +1,40 @@
+/// When a launched clip or scene starts playing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LaunchQuantize {
+    Immediate,
+    /// On the next beat
+    Beat,
+    /// On the next quantum boundary, i.e. the next bar of the Link session
+    Bar,
+    /// On the next boundary of a phrase of this many bars
+    Bars(u32),
+}
+
+impl LaunchQuantize {
+    /// The beat at which a clip launched at `beat` starts. Bar lines are multiples of `quantum`
+    /// on the Link timeline, so every peer in the session agrees on them.
+    pub fn boundary(&self, beat: f64, quantum: f64) -> f64 {
+        let grid = match *self {
+            LaunchQuantize::Immediate => return beat,
+            LaunchQuantize::Beat => 1.,
+            LaunchQuantize::Bar => quantum,
+            LaunchQuantize::Bars(bars) => quantum * bars.max(1) as f64,
+        };
+        (beat / grid).ceil() * grid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn launch_boundaries() {
+        assert_eq!(LaunchQuantize::Immediate.boundary(5.3, 4.), 5.3);
+        assert_eq!(LaunchQuantize::Beat.boundary(5.3, 4.), 6.);
+        assert_eq!(LaunchQuantize::Bar.boundary(5.3, 4.), 8.);
+        assert_eq!(LaunchQuantize::Bar.boundary(8., 4.), 8.);
+        assert_eq!(LaunchQuantize::Bars(4).boundary(5.3, 3.), 12.);
+        assert_eq!(LaunchQuantize::Bars(2).boundary(-1., 4.), 0.);
+    }
+}
@@ -7,21 +7,40 @@ use rusty_link::{AblLink, SessionState};
 
 use std::sync::{mpsc, Arc, Mutex};
 
+mod arpeggiator;
 mod audio_engine;
 mod audio_platform_cpal;
+mod euclid;
+mod follow;
+mod groove;
+mod humanize;
+mod launch;
+mod midi_clock;
+mod midi_input;
+mod mtc;
+mod quantize;
+mod random;
+mod recorder;
+mod scale;
 mod sequencer;
+mod smf;
+mod song;
+mod step;
+mod tap_tempo;
+mod timing;
+mod transpose;
 
 pub struct State {
-    pub link: AblLink,
+    pub link: &'static AblLink,
     pub session_state: SessionState,
     pub running: bool,
     pub quantum: f64,
 }
 
 impl State {
-    pub fn new() -> Self {
+    pub fn new(link: &'static AblLink) -> Self {
         Self {
-            link: AblLink::new(120.),
+            link,
             session_state: SessionState::new(),
             running: true,
             quantum: 4.,
@@ -58,13 +77,23 @@ fn main() {
     let (_input_tx, input_rx) = mpsc::channel::<UpdateSessionState>();
     let quantum = Arc::new(Mutex::new(4.));
     let quantum_clone2 = Arc::clone(&quantum);
-    let _audio_engine = AudioEngine::new(&ABL_LINK, audio_platform, input_rx, quantum_clone2);
+    let _audio_engine = AudioEngine::new(Some(&*ABL_LINK), audio_platform, input_rx, quantum_clone2);
 
-    // init Link state
-    let mut state = State::new();
+    // init Link state, the session is shared with the audio engine
+    let mut state = State::new(&ABL_LINK);
 
-    ABL_LINK.set_tempo_callback(|tempo| println!("tempo: {}", tempo));
+    state
+        .link
+        .set_tempo_callback(|tempo| println!("tempo: {}", tempo));
+    state
+        .link
+        .set_num_peers_callback(|peers| println!("peers: {}", peers));
+    state
+        .link
+        .set_start_stop_callback(|playing| println!("playing: {}", playing));
 
+    // starting and stopping is shared with the peers
+    state.link.enable_start_stop_sync(true);
     println!("link enabled!");
     state.link.enable(true);
 
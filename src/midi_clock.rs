@@ -0,0 +1,336 @@
+use crate::sequencer::MidiEvent;
+use crate::timing::{TimingSource, DEFAULT_TEMPO};
+use std::convert::TryFrom;
+use wmidi::{MidiMessage, U14};
+
+/// MIDI beat clock runs at 24 pulses per quarter note.
+pub const PULSES_PER_BEAT: i64 = 24;
+
+/// Song position pointers count sixteenth notes, which are 6 clock pulses long.
+const PULSES_PER_SIXTEENTH: i64 = 6;
+
+/// Song position pointers are 14 bit, positions further out wrap around.
+const SONG_POSITIONS: i64 = 1 << 14;
+
+/// Generates MIDI beat clock from the beat position of the timeline. Pulses are sent all the
+/// time, so that stopped devices follow the tempo. Start, Stop and Continue follow the play
+/// state, and a song position pointer is sent when playback starts elsewhere than at the
+/// start of the song, or jumps.
+pub struct ClockOutput {
+    playing: bool,
+    /// Start or Continue waiting to be sent right before this clock pulse
+    pending: Option<(i64, MidiMessage<'static>)>,
+    /// Beat at which the last rendered buffer ended
+    last_end: Option<f64>,
+}
+
+impl ClockOutput {
+    pub fn new() -> Self {
+        Self {
+            playing: false,
+            pending: None,
+            last_end: None,
+        }
+    }
+
+    /// Clock for a buffer of `buffer_size` samples that starts at `beat`. Offsets are in
+    /// samples from the start of the buffer.
+    pub fn render(
+        &mut self,
+        beat: f64,
+        playing: bool,
+        buffer_size: f64,
+        samples_per_beat: f64,
+        midi: &mut Vec<MidiEvent<'static>>,
+    ) {
+        let end = beat + buffer_size / samples_per_beat;
+        // a buffer that doesn't continue where the last one ended is a jump
+        let jumped = match self.last_end {
+            Some(last_end) => (beat - last_end).abs() > end - beat,
+            None => true,
+        };
+        self.last_end = Some(end);
+
+        match (self.playing, playing) {
+            (false, true) => self.locate(beat, midi),
+            (true, false) => {
+                self.pending = None;
+                midi.push(MidiEvent::new(0., MidiMessage::Stop));
+            }
+            (true, true) if jumped => {
+                // devices only take a song position pointer while they are stopped
+                midi.push(MidiEvent::new(0., MidiMessage::Stop));
+                self.locate(beat, midi);
+            }
+            _ => {}
+        }
+        self.playing = playing;
+
+        let mut pulse = (beat * PULSES_PER_BEAT as f64).ceil() as i64;
+        while (pulse as f64) < end * PULSES_PER_BEAT as f64 {
+            let offset = (pulse as f64 / PULSES_PER_BEAT as f64 - beat) * samples_per_beat;
+            if let Some((at, message)) = self.pending.clone() {
+                if at == pulse {
+                    midi.push(MidiEvent::new(offset, message));
+                    self.pending = None;
+                }
+            }
+            midi.push(MidiEvent::new(offset, MidiMessage::TimingClock));
+            pulse += 1;
+        }
+    }
+
+    /// Playback starts at `beat`. Devices can only be positioned on sixteenth notes, so they are
+    /// started on the next one, and never before the start of the song.
+    fn locate(&mut self, beat: f64, midi: &mut Vec<MidiEvent<'static>>) {
+        let sixteenth = ((beat * 4.).ceil() as i64).max(0);
+        let pulse = sixteenth * PULSES_PER_SIXTEENTH;
+        if sixteenth == 0 {
+            self.pending = Some((pulse, MidiMessage::Start));
+        } else {
+            let position = U14::try_from((sixteenth % SONG_POSITIONS) as u16).unwrap_or(U14::MIN);
+            midi.push(MidiEvent::new(
+                0.,
+                MidiMessage::SongPositionPointer(position),
+            ));
+            self.pending = Some((pulse, MidiMessage::Continue));
+        }
+    }
+}
+
+/// Intervals between clock pulses are averaged over roughly this many pulses, so that jitter
+/// of the sending device and the MIDI interface doesn't show in the tempo.
+const SMOOTHING: f64 = 24.;
+
+/// A pulse that arrives this much later or earlier than expected means the clock was stopped
+/// or changed its tempo abruptly, the average starts over.
+const MAX_DEVIATION: f64 = 0.5;
+
+/// Follows an external MIDI beat clock. Times are in microseconds on any clock, e.g. the Link
+/// clock, as long as it's the same for every call.
+pub struct ClockInput {
+    playing: bool,
+    /// Clock pulses from the start of the song to the last pulse received, pulses received
+    /// while stopped don't move it
+    position: i64,
+    /// Time of the last pulse
+    last_pulse: Option<i64>,
+    /// Averaged interval between pulses in microseconds
+    interval: Option<f64>,
+}
+
+impl ClockInput {
+    pub fn new() -> Self {
+        Self {
+            playing: false,
+            position: -1,
+            last_pulse: None,
+            interval: None,
+        }
+    }
+
+    /// Handles a message received at `time`. Returns whether it was a clock or transport
+    /// message.
+    pub fn receive(&mut self, message: &MidiMessage, time: i64) -> bool {
+        match message {
+            MidiMessage::TimingClock => self.pulse(time),
+            // the first pulse after Start is the start of the song
+            MidiMessage::Start => {
+                self.position = -1;
+                self.playing = true;
+            }
+            MidiMessage::Continue => self.playing = true,
+            MidiMessage::Stop => self.playing = false,
+            // the first pulse after Continue is at the song position
+            MidiMessage::SongPositionPointer(position) => {
+                self.position = u16::from(*position) as i64 * PULSES_PER_SIXTEENTH - 1;
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn pulse(&mut self, time: i64) {
+        if let Some(last_pulse) = self.last_pulse {
+            let interval = (time - last_pulse) as f64;
+            self.interval = match self.interval {
+                Some(average) if (interval - average).abs() <= average * MAX_DEVIATION => {
+                    Some(average + (interval - average) / SMOOTHING)
+                }
+                _ => Some(interval),
+            };
+        }
+        self.last_pulse = Some(time);
+        if self.playing {
+            self.position += 1;
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    /// Tempo in beats per minute, once two pulses have been received.
+    pub fn measured_tempo(&self) -> Option<f64> {
+        self.interval
+            .filter(|interval| *interval > 0.)
+            .map(|interval| 60.0e6 / (interval * PULSES_PER_BEAT as f64))
+    }
+
+    /// The beat position at `time`. While playing, the position moves on from the last pulse at
+    /// the measured tempo, also past the next pulse, so that it runs smoothly when it's asked
+    /// for ahead of time, e.g. to make up for output latency.
+    pub fn beat_at_time(&self, time: i64) -> f64 {
+        let beat = self.position as f64 / PULSES_PER_BEAT as f64;
+        match (self.playing, self.last_pulse, self.interval) {
+            (true, Some(last_pulse), Some(interval)) if interval > 0. => {
+                let pulses = ((time - last_pulse) as f64 / interval).max(0.);
+                beat + pulses / PULSES_PER_BEAT as f64
+            }
+            _ => beat,
+        }
+    }
+}
+
+impl TimingSource for ClockInput {
+    fn tempo(&self) -> f64 {
+        self.measured_tempo().unwrap_or(DEFAULT_TEMPO)
+    }
+
+    /// MIDI clock has no notion of bars, the quantum is ignored.
+    fn beat_at_time(&self, time: i64, _quantum: f64) -> f64 {
+        ClockInput::beat_at_time(self, time)
+    }
+
+    fn is_playing(&self, _time: i64) -> bool {
+        self.playing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES_PER_BEAT: f64 = 22050.;
+
+    fn render(
+        clock: &mut ClockOutput,
+        beat: f64,
+        playing: bool,
+    ) -> Vec<(f64, MidiMessage<'static>)> {
+        let mut midi = Vec::new();
+        clock.render(beat, playing, 512., SAMPLES_PER_BEAT, &mut midi);
+        midi.into_iter()
+            .map(|event| (event.offset(), event.message().clone()))
+            .collect()
+    }
+
+    #[test]
+    fn pulses_are_sample_accurate_across_buffers() {
+        let mut clock = ClockOutput::new();
+        let buffer = 512. / SAMPLES_PER_BEAT;
+        let mut pulses = Vec::new();
+        for index in 0..200 {
+            let beat = index as f64 * buffer;
+            for (offset, message) in render(&mut clock, beat, false) {
+                assert_eq!(message, MidiMessage::TimingClock);
+                pulses.push(beat * SAMPLES_PER_BEAT + offset);
+            }
+        }
+        // 200 buffers of 512 samples hold 111.45 pulses at 918.75 samples each
+        assert_eq!(pulses.len(), 112);
+        for (count, position) in pulses.iter().enumerate() {
+            assert!((position - count as f64 * 918.75).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn transport_and_song_position() {
+        let mut clock = ClockOutput::new();
+        render(&mut clock, -0.01, false);
+        // starting at the start of the song sends Start right before the first pulse
+        let started = render(&mut clock, -0.01, true);
+        assert_eq!(
+            started[..2],
+            [
+                (220.5, MidiMessage::Start),
+                (220.5, MidiMessage::TimingClock)
+            ]
+        );
+
+        let stopped = render(&mut clock, 2., false);
+        assert_eq!(stopped[0], (0., MidiMessage::Stop));
+
+        // starting between sixteenth notes continues from the next one
+        let continued = render(&mut clock, 8.2, true);
+        let position = U14::try_from(33).unwrap();
+        assert_eq!(
+            continued[0],
+            (0., MidiMessage::SongPositionPointer(position))
+        );
+        assert!(!continued.iter().any(|(_, m)| *m == MidiMessage::Continue));
+        let mut beat = 8.2 + 512. / SAMPLES_PER_BEAT;
+        let mut messages = Vec::new();
+        while beat < 8.3 {
+            messages.extend(render(&mut clock, beat, true));
+            beat += 512. / SAMPLES_PER_BEAT;
+        }
+        let continue_at = messages
+            .iter()
+            .position(|(_, m)| *m == MidiMessage::Continue)
+            .unwrap();
+        // the pulse at beat 8.25 follows the Continue
+        assert_eq!(messages[continue_at + 1].1, MidiMessage::TimingClock);
+        assert_eq!(messages[continue_at].0, messages[continue_at + 1].0);
+
+        // a jump while playing stops, positions and continues the device
+        let jumped = render(&mut clock, 16., true);
+        let position = U14::try_from(64).unwrap();
+        assert_eq!(
+            jumped[..4],
+            [
+                (0., MidiMessage::Stop),
+                (0., MidiMessage::SongPositionPointer(position)),
+                (0., MidiMessage::Continue),
+                (0., MidiMessage::TimingClock),
+            ]
+        );
+    }
+
+    #[test]
+    fn follow_tempo_and_position() {
+        let mut clock = ClockInput::new();
+        // 120 bpm with a quarter millisecond of jitter
+        let interval = 500_000. / 24.;
+        let time = |pulse: i64| (pulse as f64 * interval) as i64 + (pulse % 2) * 250;
+        assert_eq!(clock.measured_tempo(), None);
+        for pulse in 0..48 {
+            clock.receive(&MidiMessage::TimingClock, time(pulse));
+        }
+        let tempo = clock.measured_tempo().unwrap();
+        assert!((tempo - 120.).abs() < 1., "{}", tempo);
+        // pulses while stopped only give the tempo
+        assert_eq!(clock.beat_at_time(time(48)), -1. / 24.);
+
+        assert!(clock.receive(&MidiMessage::Start, time(48)));
+        for pulse in 49..=97 {
+            clock.receive(&MidiMessage::TimingClock, time(pulse));
+        }
+        assert!(clock.is_playing());
+        assert_eq!(clock.beat_at_time(time(97)), 2.);
+        // halfway to the next pulse, and on at the same tempo past it
+        let halfway = clock.beat_at_time(time(97) + interval as i64 / 2);
+        assert!((halfway - (2. + 0.5 / 24.)).abs() < 0.001, "{}", halfway);
+        let ahead = clock.beat_at_time(time(100));
+        assert!((ahead - (2. + 3. / 24.)).abs() < 0.001, "{}", ahead);
+
+        clock.receive(&MidiMessage::Stop, time(98));
+        assert_eq!(clock.beat_at_time(time(100)), 2.);
+        let position = U14::try_from(16).unwrap();
+        clock.receive(&MidiMessage::SongPositionPointer(position), time(98));
+        clock.receive(&MidiMessage::Continue, time(98));
+        clock.receive(&MidiMessage::TimingClock, time(99));
+        assert_eq!(clock.beat_at_time(time(99)), 4.);
+        assert!(!clock.receive(&MidiMessage::Reset, time(99)));
+    }
+}
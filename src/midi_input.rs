@@ -0,0 +1,48 @@
+use std::convert::TryFrom;
+use wmidi::MidiMessage;
+
+/// A MIDI message received on the input port, with the host time it was received at.
+#[derive(Clone, Debug)]
+pub struct MidiInputEvent {
+    pub timestamp: u64,
+    pub message: MidiMessage<'static>,
+}
+
+/// Splits the data of a MIDI packet into messages. A packet can hold several messages, and
+/// anything that can't be parsed ends the packet.
+pub fn messages(mut data: &[u8]) -> Vec<MidiMessage<'static>> {
+    let mut messages = Vec::new();
+    while let Ok(message) = MidiMessage::try_from(data) {
+        let size = message.bytes_size().max(1).min(data.len());
+        messages.push(message.to_owned());
+        data = &data[size..];
+    }
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wmidi::{Channel, Note, U7};
+
+    #[test]
+    fn split_packet() {
+        let data = [0x90, 60, 100, 0xF8, 0x80, 60, 0, 0xC1, 5];
+        let messages = messages(&data);
+        assert_eq!(
+            messages,
+            vec![
+                MidiMessage::NoteOn(Channel::Ch1, Note::C4, U7::from_u8_lossy(100)),
+                MidiMessage::TimingClock,
+                MidiMessage::NoteOff(Channel::Ch1, Note::C4, U7::MIN),
+                MidiMessage::ProgramChange(Channel::Ch2, U7::from_u8_lossy(5)),
+            ]
+        );
+    }
+
+    #[test]
+    fn split_packet_stops_at_garbage() {
+        let data = [0x90, 60, 100, 60, 100];
+        assert_eq!(messages(&data).len(), 1);
+    }
+}
@@ -0,0 +1,433 @@
+use crate::sequencer::MidiEvent;
+use crate::timing::{TimingSource, DEFAULT_TEMPO};
+use wmidi::{MidiMessage, U7};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameRate {
+    Fps24,
+    Fps25,
+    /// 29.97 frames per second, with drop frame numbering
+    Fps2997Drop,
+    Fps30,
+}
+
+impl FrameRate {
+    /// Frames per second of real time
+    pub fn fps(&self) -> f64 {
+        match self {
+            FrameRate::Fps24 => 24.,
+            FrameRate::Fps25 => 25.,
+            FrameRate::Fps2997Drop => 30000. / 1001.,
+            FrameRate::Fps30 => 30.,
+        }
+    }
+
+    /// Frames per second of the timecode numbering
+    fn nominal(&self) -> i64 {
+        match self {
+            FrameRate::Fps24 => 24,
+            FrameRate::Fps25 => 25,
+            FrameRate::Fps2997Drop | FrameRate::Fps30 => 30,
+        }
+    }
+
+    /// Rate bits of the timecode messages
+    fn code(&self) -> u8 {
+        match self {
+            FrameRate::Fps24 => 0,
+            FrameRate::Fps25 => 1,
+            FrameRate::Fps2997Drop => 2,
+            FrameRate::Fps30 => 3,
+        }
+    }
+
+    fn from_code(code: u8) -> Self {
+        match code & 3 {
+            0 => FrameRate::Fps24,
+            1 => FrameRate::Fps25,
+            2 => FrameRate::Fps2997Drop,
+            _ => FrameRate::Fps30,
+        }
+    }
+
+    fn frames_per_day(&self) -> i64 {
+        match self {
+            // 17982 frames in every ten minutes
+            FrameRate::Fps2997Drop => 17982 * 6 * 24,
+            rate => rate.nominal() * 86400,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timecode {
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+    pub frames: u8,
+}
+
+impl Timecode {
+    /// The timecode of the frame with this number, counted from zero. Timecode wraps around
+    /// after 24 hours. With drop frame, frame numbers 0 and 1 are skipped at the start of every
+    /// minute, except every tenth minute.
+    pub fn from_frame(frame: i64, rate: FrameRate) -> Self {
+        let mut frame = frame.rem_euclid(rate.frames_per_day());
+        if rate == FrameRate::Fps2997Drop {
+            let (tens, rest) = (frame / 17982, frame % 17982);
+            frame += 18 * tens;
+            if rest > 1 {
+                frame += 2 * ((rest - 2) / 1798);
+            }
+        }
+        let nominal = rate.nominal();
+        Self {
+            hours: (frame / (nominal * 3600) % 24) as u8,
+            minutes: (frame / (nominal * 60) % 60) as u8,
+            seconds: (frame / nominal % 60) as u8,
+            frames: (frame % nominal) as u8,
+        }
+    }
+
+    /// The number of the frame, counted from zero.
+    pub fn frame(&self, rate: FrameRate) -> i64 {
+        let minutes = self.hours as i64 * 60 + self.minutes as i64;
+        let frame = (minutes * 60 + self.seconds as i64) * rate.nominal() + self.frames as i64;
+        match rate {
+            FrameRate::Fps2997Drop => frame - 2 * (minutes - minutes / 10),
+            _ => frame,
+        }
+    }
+}
+
+/// A full frame message, which positions a device while the timecode isn't running.
+fn full_frame(timecode: Timecode, rate: FrameRate) -> MidiMessage<'static> {
+    let data = [
+        0x7F,
+        // all devices
+        0x7F,
+        0x01,
+        0x01,
+        rate.code() << 5 | timecode.hours,
+        timecode.minutes,
+        timecode.seconds,
+        timecode.frames,
+    ];
+    MidiMessage::OwnedSysEx(data.iter().map(|byte| U7::from_u8_lossy(*byte)).collect())
+}
+
+/// Generates MIDI time code for the transport. The eight quarter frames that make up a
+/// timecode are spread over two frames, and carry the timecode of the first one.
+pub struct MtcOutput {
+    rate: FrameRate,
+    running: bool,
+    /// Transport time at the end of the last rendered buffer, in seconds
+    seconds: f64,
+    /// Beat at which the last rendered buffer ended
+    last_end: Option<f64>,
+}
+
+impl MtcOutput {
+    pub fn new(rate: FrameRate) -> Self {
+        Self {
+            rate,
+            running: false,
+            seconds: 0.,
+            last_end: None,
+        }
+    }
+
+    pub fn set_rate(&mut self, rate: FrameRate) {
+        self.rate = rate;
+    }
+
+    /// Timecode for a buffer of `buffer_size` samples that starts at `beat`. The transport time
+    /// is taken from the beat position when playback starts or jumps, and runs on in real time
+    /// after that, so tempo changes don't make the timecode jump. During a count-in at negative
+    /// beats the timecode waits, and starts with a full frame at beat zero.
+    pub fn render(
+        &mut self,
+        beat: f64,
+        tempo: f64,
+        playing: bool,
+        buffer_size: f64,
+        sample_rate: f64,
+        midi: &mut Vec<MidiEvent<'static>>,
+    ) {
+        let end_beat = beat + buffer_size * tempo / (60. * sample_rate);
+        let located = match self.last_end {
+            Some(last_end) => (beat - last_end).abs() > end_beat - beat,
+            None => true,
+        };
+        self.last_end = Some(end_beat);
+
+        if playing && end_beat <= 0. {
+            self.running = false;
+            return;
+        }
+        if located || (playing && !self.running) {
+            let seconds = beat * 60. / tempo;
+            // a count-in that ends in this buffer starts the timecode at beat zero
+            let (seconds, offset) = match playing {
+                true => (seconds, (-seconds * sample_rate).max(0.)),
+                false => (seconds.max(0.), 0.),
+            };
+            self.seconds = seconds;
+            let frame = (seconds.max(0.) * self.rate.fps()).floor() as i64;
+            let timecode = Timecode::from_frame(frame, self.rate);
+            midi.push(MidiEvent::new(offset, full_frame(timecode, self.rate)));
+        }
+        self.running = playing;
+
+        let start = self.seconds;
+        let end = start + buffer_size / sample_rate;
+        self.seconds = end;
+        if !playing {
+            return;
+        }
+        let quarter_frames = 4. * self.rate.fps();
+        let mut quarter = ((start * quarter_frames).ceil() as i64).max(0);
+        while (quarter as f64) < end * quarter_frames {
+            let offset = (quarter as f64 / quarter_frames - start) * sample_rate;
+            midi.push(MidiEvent::new(offset, self.quarter_frame(quarter)));
+            quarter += 1;
+        }
+    }
+
+    /// The quarter frame message with this number, counted from zero.
+    fn quarter_frame(&self, quarter: i64) -> MidiMessage<'static> {
+        let piece = quarter.rem_euclid(8) as u8;
+        let timecode = Timecode::from_frame(quarter.div_euclid(8) * 2, self.rate);
+        let value = match piece {
+            0 => timecode.frames & 0xF,
+            1 => timecode.frames >> 4,
+            2 => timecode.seconds & 0xF,
+            3 => timecode.seconds >> 4,
+            4 => timecode.minutes & 0xF,
+            5 => timecode.minutes >> 4,
+            6 => timecode.hours & 0xF,
+            _ => self.rate.code() << 1 | timecode.hours >> 4,
+        };
+        MidiMessage::MidiTimeCode(U7::from_u8_lossy(piece << 4 | value))
+    }
+}
+
+/// Timecode that stops running when no quarter frame arrives for this many frames.
+const DROPOUT_FRAMES: f64 = 4.;
+
+/// Chases MIDI time code on the input. Times are in microseconds on any clock, as long as it's
+/// the same for every call.
+pub struct MtcInput {
+    rate: FrameRate,
+    /// Values of the quarter frames received so far, by piece
+    pieces: [u8; 8],
+    /// Bit mask of the pieces received since the last piece 0
+    received: u8,
+    /// Transport time in seconds, and the time it was received at
+    position: Option<(f64, i64)>,
+    /// Time of the last quarter frame
+    last_quarter_frame: Option<i64>,
+    /// Time code has no tempo, beats are counted at this one
+    tempo: f64,
+}
+
+impl MtcInput {
+    pub fn new() -> Self {
+        Self {
+            rate: FrameRate::Fps30,
+            pieces: [0; 8],
+            received: 0,
+            position: None,
+            last_quarter_frame: None,
+            tempo: DEFAULT_TEMPO,
+        }
+    }
+
+    pub fn set_tempo(&mut self, tempo: f64) {
+        self.tempo = tempo;
+    }
+
+    /// Handles a message received at `time`. Returns whether it was a timecode message.
+    pub fn receive(&mut self, message: &MidiMessage, time: i64) -> bool {
+        match message {
+            MidiMessage::MidiTimeCode(data) => self.quarter_frame(u8::from(*data), time),
+            MidiMessage::SysEx(data) => return self.full_frame(data, time),
+            MidiMessage::OwnedSysEx(data) => return self.full_frame(data, time),
+            _ => return false,
+        }
+        true
+    }
+
+    fn quarter_frame(&mut self, data: u8, time: i64) {
+        let piece = (data >> 4 & 7) as usize;
+        self.pieces[piece] = data & 0xF;
+        self.received = match piece {
+            0 => 1,
+            _ => self.received | 1 << piece,
+        };
+        self.last_quarter_frame = Some(time);
+        if piece == 7 && self.received == 0xFF {
+            let pieces = &self.pieces;
+            self.rate = FrameRate::from_code(pieces[7] >> 1);
+            let timecode = Timecode {
+                hours: (pieces[7] & 1) << 4 | pieces[6],
+                minutes: pieces[5] << 4 | pieces[4],
+                seconds: pieces[3] << 4 | pieces[2],
+                frames: pieces[1] << 4 | pieces[0],
+            };
+            // the last quarter frame is sent three quarters into the second frame
+            let frame = timecode.frame(self.rate) as f64 + 1.75;
+            self.position = Some((frame / self.rate.fps(), time));
+        }
+    }
+
+    fn full_frame(&mut self, data: &[U7], time: i64) -> bool {
+        let data: Vec<u8> = data.iter().map(|byte| u8::from(*byte)).collect();
+        match data[..] {
+            [0x7F, _, 0x01, 0x01, hours, minutes, seconds, frames] => {
+                self.rate = FrameRate::from_code(hours >> 5);
+                let timecode = Timecode {
+                    hours: hours & 0x1F,
+                    minutes,
+                    seconds,
+                    frames,
+                };
+                let frame = timecode.frame(self.rate) as f64;
+                self.position = Some((frame / self.rate.fps(), time));
+                self.last_quarter_frame = None;
+                self.received = 0;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn rate(&self) -> FrameRate {
+        self.rate
+    }
+
+    /// Whether quarter frames are coming in.
+    pub fn is_running(&self, time: i64) -> bool {
+        let dropout = DROPOUT_FRAMES / self.rate.fps() * 1.0e6;
+        self.last_quarter_frame
+            .is_some_and(|last| ((time - last) as f64) < dropout)
+    }
+
+    /// Transport time in seconds at `time`, once a timecode has been received.
+    pub fn seconds_at_time(&self, time: i64) -> Option<f64> {
+        let (seconds, received) = self.position?;
+        match self.is_running(time) {
+            true => Some(seconds + (time - received) as f64 / 1.0e6),
+            false => Some(seconds),
+        }
+    }
+}
+
+impl TimingSource for MtcInput {
+    fn tempo(&self) -> f64 {
+        self.tempo
+    }
+
+    /// Beats are counted from the zero time code, the quantum is ignored.
+    fn beat_at_time(&self, time: i64, _quantum: f64) -> f64 {
+        self.seconds_at_time(time).unwrap_or(0.) * self.tempo / 60.
+    }
+
+    fn is_playing(&self, time: i64) -> bool {
+        self.is_running(time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drop_frame_numbering() {
+        let timecode = |frame| {
+            let timecode = Timecode::from_frame(frame, FrameRate::Fps2997Drop);
+            assert_eq!(timecode.frame(FrameRate::Fps2997Drop), frame);
+            (
+                timecode.hours,
+                timecode.minutes,
+                timecode.seconds,
+                timecode.frames,
+            )
+        };
+        assert_eq!(timecode(1799), (0, 0, 59, 29));
+        assert_eq!(timecode(1800), (0, 1, 0, 2));
+        assert_eq!(timecode(17981), (0, 9, 59, 29));
+        assert_eq!(timecode(17982), (0, 10, 0, 0));
+        assert_eq!(timecode(17982 * 6), (1, 0, 0, 0));
+
+        let timecode = Timecode::from_frame(90_000, FrameRate::Fps25);
+        assert_eq!(timecode.hours, 1);
+        assert_eq!(timecode.frame(FrameRate::Fps25), 90_000);
+    }
+
+    #[test]
+    fn chase_generated_timecode() {
+        for rate in [
+            FrameRate::Fps24,
+            FrameRate::Fps25,
+            FrameRate::Fps2997Drop,
+            FrameRate::Fps30,
+        ] {
+            let mut output = MtcOutput::new(rate);
+            let mut input = MtcInput::new();
+            let sample_rate = 44100.;
+            let buffer = 512. / sample_rate;
+            // start at 10 minutes and 2 seconds into the song, at 120 bpm
+            let start = 1204.;
+            let mut time = 0;
+            for index in 0..400 {
+                let beat = start + index as f64 * buffer * 2.;
+                let mut midi = Vec::new();
+                output.render(beat, 120., true, 512., sample_rate, &mut midi);
+                if index == 0 {
+                    assert!(matches!(midi[0].message(), MidiMessage::OwnedSysEx(_)));
+                }
+                for event in midi {
+                    time = ((index as f64 * buffer + event.offset() / sample_rate) * 1.0e6) as i64;
+                    assert!(input.receive(event.message(), time));
+                }
+            }
+            assert_eq!(input.rate(), rate);
+            assert!(input.is_running(time));
+            let expected = 602. + time as f64 / 1.0e6;
+            let seconds = input.seconds_at_time(time).unwrap();
+            assert!((seconds - expected).abs() < 1. / rate.fps(), "{:?}", rate);
+        }
+    }
+
+    #[test]
+    fn count_in_holds_timecode() {
+        let mut output = MtcOutput::new(FrameRate::Fps25);
+        let mut input = MtcInput::new();
+        let sample_rate = 44100.;
+        let buffer = 512. / sample_rate;
+        // a two beat count-in at 120 bpm, beat zero is one second in
+        let micros = |seconds: f64| (seconds * 1.0e6) as i64;
+        let mut downbeat = None;
+        for index in 0..130 {
+            let beat = -2. + index as f64 * buffer * 2.;
+            let mut midi = Vec::new();
+            output.render(beat, 120., true, 512., sample_rate, &mut midi);
+            if beat + buffer * 2. <= 0. {
+                assert!(midi.is_empty());
+            }
+            for event in midi {
+                let time = index as f64 * buffer + event.offset() / sample_rate;
+                if downbeat.is_none() {
+                    assert!(matches!(event.message(), MidiMessage::OwnedSysEx(_)));
+                    downbeat = Some(time);
+                }
+                assert!(input.receive(event.message(), micros(time)));
+            }
+        }
+        assert!((downbeat.unwrap() - 1.).abs() < 1.0e-6);
+        // beat 0.5 is a quarter second into the song
+        let seconds = input.seconds_at_time(micros(1.25)).unwrap();
+        assert!((seconds - 0.25).abs() < 1. / 25., "{}", seconds);
+    }
+}
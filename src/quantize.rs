@@ -0,0 +1,123 @@
+use crate::sequencer::SequencerNote;
+
+/// Moves notes towards a grid. A 1/16 grid is `ppq / 4` ticks, 1/8 triplets are `ppq / 3`.
+#[derive(Clone, Debug)]
+pub struct Quantize {
+    /// Grid resolution in ticks
+    grid: i64,
+    /// How far notes are moved towards the grid, in percent
+    strength: f64,
+    /// Position of every other grid line within a pair, in percent, as with `Swing`
+    swing: f64,
+    /// Notes further than this many ticks from the grid are left alone
+    window: Option<f64>,
+    /// Whether note lengths are quantized to the grid as well
+    lengths: bool,
+}
+
+impl Quantize {
+    pub fn new(grid: i64) -> Self {
+        Self {
+            grid: grid.max(1),
+            strength: 100.,
+            swing: 50.,
+            window: None,
+            lengths: false,
+        }
+    }
+
+    pub fn set_strength(&mut self, strength: f64) {
+        self.strength = strength.clamp(0., 100.);
+    }
+
+    pub fn set_swing(&mut self, swing: f64) {
+        self.swing = swing.clamp(50., 75.);
+    }
+
+    pub fn set_window(&mut self, window: Option<f64>) {
+        self.window = window;
+    }
+
+    pub fn set_lengths(&mut self, lengths: bool) {
+        self.lengths = lengths;
+    }
+
+    /// The position of the grid line closest to `tick`.
+    fn grid_line(&self, tick: f64) -> f64 {
+        let grid = self.grid as f64;
+        let pair = grid * 2.;
+        let pair_start = (tick / pair).floor() * pair;
+        let off_beat = pair_start + pair * self.swing / 100.;
+        [pair_start, off_beat, pair_start + pair]
+            .into_iter()
+            .min_by(|a, b| (a - tick).abs().total_cmp(&(b - tick).abs()))
+            .unwrap()
+    }
+
+    /// Position of a note that starts at `tick`.
+    pub fn position(&self, tick: f64) -> f64 {
+        let distance = self.grid_line(tick) - tick;
+        if self.window.is_some_and(|window| distance.abs() > window) {
+            return tick;
+        }
+        tick + distance * self.strength / 100.
+    }
+
+    /// Length of a note that is `length` ticks long. Lengths are rounded to whole grid steps
+    /// of at least one step, if lengths are quantized.
+    pub fn length(&self, length: f64) -> f64 {
+        if !self.lengths {
+            return length;
+        }
+        let grid = self.grid as f64;
+        let target = (length / grid).round().max(1.) * grid;
+        length + (target - length) * self.strength / 100.
+    }
+
+    /// The timing offset in ticks for a note, and the note with its length quantized.
+    pub fn note(&self, note: &SequencerNote) -> (f64, SequencerNote) {
+        let tick = note.tick() as f64;
+        let length = self.length(note.length() as f64).round() as i64;
+        let note = SequencerNote::new(
+            note.channel(),
+            note.note(),
+            note.velocity(),
+            note.tick(),
+            length.max(1),
+        );
+        (self.position(tick) - tick, note)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strength_and_window() {
+        let mut quantize = Quantize::new(24);
+        assert_eq!(quantize.position(30.), 24.);
+        assert_eq!(quantize.position(40.), 48.);
+
+        quantize.set_strength(50.);
+        assert_eq!(quantize.position(30.), 27.);
+
+        quantize.set_window(Some(4.));
+        assert_eq!(quantize.position(27.), 25.5);
+        assert_eq!(quantize.position(30.), 30.);
+    }
+
+    #[test]
+    fn swing_and_lengths() {
+        let mut quantize = Quantize::new(24);
+        quantize.set_swing(75.);
+        assert_eq!(quantize.position(30.), 36.);
+        assert_eq!(quantize.position(44.), 48.);
+        assert_eq!(quantize.length(30.), 30.);
+
+        quantize.set_lengths(true);
+        assert_eq!(quantize.length(30.), 24.);
+        assert_eq!(quantize.length(5.), 24.);
+        assert_eq!(quantize.length(40.), 48.);
+    }
+}
@@ -0,0 +1,44 @@
+/// Stateless, seeded random numbers. The same seed and keys always give the same value, so
+/// render-time randomization is reproducible and doesn't depend on how the timeline is split
+/// into buffers.
+pub fn unit(seed: u64, keys: &[i64]) -> f64 {
+    let mut state = seed;
+    for key in keys {
+        state = mix(state ^ *key as u64);
+    }
+    // 53 random bits in [0, 1)
+    (mix(state) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Random value in [-1, 1)
+pub fn bipolar(seed: u64, keys: &[i64]) -> f64 {
+    unit(seed, keys) * 2. - 1.
+}
+
+/// SplitMix64 finalizer
+fn mix(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unit_is_reproducible() {
+        assert_eq!(unit(1, &[2, 3]), unit(1, &[2, 3]));
+        assert_ne!(unit(1, &[2, 3]), unit(2, &[2, 3]));
+        assert_ne!(unit(1, &[2, 3]), unit(1, &[3, 2]));
+    }
+
+    #[test]
+    fn unit_range() {
+        for key in 0..1000 {
+            let value = unit(42, &[key]);
+            assert!((0. ..1.).contains(&value));
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use crate::quantize::Quantize;
+use crate::sequencer::{MIDISequence, SequencerNote};
+use wmidi::{Channel, Note, U7};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecordMode {
+    /// The first note of a take clears the sequence
+    Replace,
+    /// Recorded notes are added to the sequence
+    Overdub,
+    /// Every loop pass that is played over replaces the previous one, so the last pass is kept
+    LoopRecord,
+}
+
+/// A key that is down, waiting for its note-off to become a note.
+#[derive(Clone, Debug)]
+struct HeldNote {
+    channel: Channel,
+    note: Note,
+    velocity: U7,
+    /// Timeline position of the note-on, in ticks
+    tick: f64,
+}
+
+/// Records notes from the MIDI input into a sequence. Notes are positioned on the timeline
+/// in ticks, so they land where they were heard regardless of the buffer they arrived in.
+#[derive(Clone, Debug)]
+pub struct Recorder {
+    mode: RecordMode,
+    /// Input quantization, applied to notes as they are recorded
+    quantize: Option<Quantize>,
+    held: Vec<HeldNote>,
+    /// Loop iteration of the last note recorded in the current take
+    take: Option<i64>,
+}
+
+impl Recorder {
+    pub fn new(mode: RecordMode) -> Self {
+        Self {
+            mode,
+            quantize: None,
+            held: Vec::new(),
+            take: None,
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: RecordMode) {
+        self.mode = mode;
+    }
+
+    pub fn set_quantize(&mut self, quantize: Option<Quantize>) {
+        self.quantize = quantize;
+    }
+
+    /// Start a new take. Keys that are still down are not recorded.
+    pub fn start(&mut self) {
+        self.held.clear();
+        self.take = None;
+    }
+
+    pub fn note_on(
+        &mut self,
+        sequence: &mut MIDISequence,
+        channel: Channel,
+        note: Note,
+        velocity: U7,
+        tick: f64,
+    ) {
+        let (iteration, _) = sequence.loop_position(tick);
+        let clear = match self.mode {
+            RecordMode::Replace => self.take.is_none(),
+            RecordMode::Overdub => false,
+            RecordMode::LoopRecord => self.take != Some(iteration),
+        };
+        if clear {
+            sequence.clear_notes();
+        }
+        self.take = Some(iteration);
+
+        self.held
+            .retain(|held| held.channel != channel || held.note != note);
+        self.held.push(HeldNote {
+            channel,
+            note,
+            velocity,
+            tick,
+        });
+    }
+
+    pub fn note_off(
+        &mut self,
+        sequence: &mut MIDISequence,
+        channel: Channel,
+        note: Note,
+        tick: f64,
+    ) {
+        let index = match self
+            .held
+            .iter()
+            .position(|held| held.channel == channel && held.note == note)
+        {
+            Some(index) => index,
+            None => return,
+        };
+        let held = self.held.remove(index);
+        // an empty sequence has no room for the note
+        if sequence.length() <= 0 {
+            return;
+        }
+
+        let (_, mut position) = sequence.loop_position(held.tick);
+        let mut length = tick - held.tick;
+        if let Some(quantize) = &self.quantize {
+            position = quantize.position(position);
+            length = quantize.length(length);
+        }
+        let start = (position.round() as i64).rem_euclid(sequence.length());
+        let length = (length.round() as i64).max(1);
+        sequence.add_note(SequencerNote::new(
+            held.channel,
+            held.note,
+            held.velocity,
+            start,
+            length,
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sequencer::PPQ;
+
+    fn play(recorder: &mut Recorder, sequence: &mut MIDISequence, note: Note, tick: f64) {
+        recorder.note_on(sequence, Channel::Ch1, note, U7::MAX, tick);
+        recorder.note_off(sequence, Channel::Ch1, note, tick + 10.);
+    }
+
+    fn recorded(sequence: &MIDISequence) -> Vec<(u8, i64)> {
+        sequence
+            .notes()
+            .iter()
+            .map(|note| (u8::from(note.note()), note.tick()))
+            .collect()
+    }
+
+    #[test]
+    fn recorded_notes_wrap_to_the_loop() {
+        let mut sequence = MIDISequence::new(4 * PPQ);
+        sequence.reset(PPQ);
+        let mut recorder = Recorder::new(RecordMode::Overdub);
+        recorder.set_quantize(Some(Quantize::new(PPQ / 4)));
+
+        play(&mut recorder, &mut sequence, Note::C4, 4. * PPQ as f64 + 2.);
+        play(&mut recorder, &mut sequence, Note::D4, 4.8 * PPQ as f64);
+        assert_eq!(
+            recorded(&sequence),
+            vec![(60, 3 * PPQ), (62, 4 * PPQ - PPQ / 4)]
+        );
+        assert_eq!(sequence.notes()[0].length(), 10);
+
+        let mut empty = MIDISequence::new(0);
+        play(&mut recorder, &mut empty, Note::C4, 10.);
+        assert!(empty.notes().is_empty());
+    }
+
+    #[test]
+    fn record_modes() {
+        let take = |mode| {
+            let mut sequence = MIDISequence::new(PPQ);
+            sequence.add_note(SequencerNote::new(Channel::Ch1, Note::C2, U7::MAX, 0, 12));
+            let mut recorder = Recorder::new(mode);
+            play(&mut recorder, &mut sequence, Note::C4, 10.);
+            play(&mut recorder, &mut sequence, Note::D4, 30.);
+            play(&mut recorder, &mut sequence, Note::E4, PPQ as f64 + 20.);
+            recorded(&sequence)
+        };
+        assert_eq!(
+            take(RecordMode::Overdub),
+            vec![(36, 0), (60, 10), (62, 30), (64, 20)]
+        );
+        assert_eq!(
+            take(RecordMode::Replace),
+            vec![(60, 10), (62, 30), (64, 20)]
+        );
+        assert_eq!(take(RecordMode::LoopRecord), vec![(64, 20)]);
+    }
+}
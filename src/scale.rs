@@ -0,0 +1,206 @@
+use wmidi::Note;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Major,
+    Minor,
+    Dorian,
+    Phrygian,
+    Lydian,
+    Mixolydian,
+    Locrian,
+    MajorPentatonic,
+    MinorPentatonic,
+}
+
+impl Mode {
+    /// Pitch classes of the mode relative to the root, bit 0 is the root
+    pub fn mask(&self) -> u16 {
+        let intervals: &[u8] = match self {
+            Mode::Major => &[0, 2, 4, 5, 7, 9, 11],
+            Mode::Minor => &[0, 2, 3, 5, 7, 8, 10],
+            Mode::Dorian => &[0, 2, 3, 5, 7, 9, 10],
+            Mode::Phrygian => &[0, 1, 3, 5, 7, 8, 10],
+            Mode::Lydian => &[0, 2, 4, 6, 7, 9, 11],
+            Mode::Mixolydian => &[0, 2, 4, 5, 7, 9, 10],
+            Mode::Locrian => &[0, 1, 3, 5, 6, 8, 10],
+            Mode::MajorPentatonic => &[0, 2, 4, 7, 9],
+            Mode::MinorPentatonic => &[0, 3, 5, 7, 10],
+        };
+        intervals
+            .iter()
+            .fold(0, |mask, interval| mask | 1 << interval)
+    }
+}
+
+/// A root and a set of pitch classes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Scale {
+    /// Pitch class of the root, 0 is C
+    root: u8,
+    /// 12 bit mask of the pitch classes in the scale, relative to the root
+    mask: u16,
+}
+
+impl Scale {
+    pub fn new(root: u8, mode: Mode) -> Self {
+        Self::custom(root, mode.mask())
+    }
+
+    /// A scale from a 12 bit mask, bit 0 being the root. The root is always part of the scale.
+    pub fn custom(root: u8, mask: u16) -> Self {
+        Self {
+            root: root % 12,
+            mask: (mask & 0xFFF) | 1,
+        }
+    }
+
+    fn contains(&self, pitch: i16) -> bool {
+        let class = (pitch - self.root as i16).rem_euclid(12);
+        self.mask & (1 << class) != 0
+    }
+
+    /// The closest note in the scale. Notes halfway between two scale notes go down.
+    pub fn quantize(&self, note: Note) -> Note {
+        let pitch = u8::from(note) as i16;
+        let nearest = (0..12)
+            .flat_map(|distance| [pitch - distance, pitch + distance])
+            .find(|pitch| (0..=127).contains(pitch) && self.contains(*pitch))
+            .unwrap_or(pitch);
+        Note::from_u8_lossy(nearest as u8)
+    }
+
+    /// The note `degrees` scale steps above `note`, which must be in the scale.
+    fn step(&self, note: Note, degrees: usize) -> Option<Note> {
+        let mut pitch = u8::from(note) as i16;
+        for _ in 0..degrees {
+            pitch = (pitch + 1..pitch + 13).find(|pitch| self.contains(*pitch))?;
+        }
+        Note::try_from(u8::try_from(pitch).ok()?).ok()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Voicing {
+    /// Stacked thirds within the smallest range
+    Close,
+    /// The second highest voice of a close chord dropped by an octave
+    Drop2,
+    /// Every other voice of a close chord raised by an octave
+    Spread,
+}
+
+/// Expands a single note into a chord of stacked scale thirds built on it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Chord {
+    /// Number of notes in the chord, 3 for triads and 4 for seventh chords
+    pub voices: usize,
+    pub voicing: Voicing,
+}
+
+impl Chord {
+    pub fn new(voices: usize, voicing: Voicing) -> Self {
+        Self { voices, voicing }
+    }
+
+    /// The notes of the chord on `note`, lowest first. Notes that end up out of the MIDI range
+    /// are left out.
+    pub fn notes(&self, scale: &Scale, note: Note) -> Vec<Note> {
+        let root = scale.quantize(note);
+        let mut notes: Vec<i16> = (0..self.voices.max(1))
+            .filter_map(|voice| scale.step(root, voice * 2))
+            .map(|note| u8::from(note) as i16)
+            .collect();
+        match self.voicing {
+            Voicing::Close => {}
+            Voicing::Drop2 if notes.len() > 1 => {
+                let index = notes.len() - 2;
+                notes[index] -= 12;
+            }
+            Voicing::Drop2 => {}
+            Voicing::Spread => {
+                for note in notes.iter_mut().skip(1).step_by(2) {
+                    *note += 12;
+                }
+            }
+        }
+        notes.sort_unstable();
+        notes
+            .into_iter()
+            .filter_map(|pitch| Note::try_from(u8::try_from(pitch).ok()?).ok())
+            .collect()
+    }
+}
+
+/// Snaps the notes of a sequence to a scale, optionally expanding them into chords.
+#[derive(Clone, Debug)]
+pub struct PitchQuantizer {
+    /// The scale to snap to, `None` follows the key of the sequencer
+    pub scale: Option<Scale>,
+    pub chord: Option<Chord>,
+}
+
+impl PitchQuantizer {
+    pub fn new(scale: Option<Scale>, chord: Option<Chord>) -> Self {
+        Self { scale, chord }
+    }
+
+    /// The notes to play for `note`, given the key of the sequencer.
+    pub fn notes(&self, key: &Scale, note: Note) -> Vec<Note> {
+        let scale = self.scale.as_ref().unwrap_or(key);
+        match &self.chord {
+            Some(chord) => chord.notes(scale, note),
+            None => vec![scale.quantize(note)],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pitches(notes: Vec<Note>) -> Vec<u8> {
+        notes.into_iter().map(u8::from).collect()
+    }
+
+    #[test]
+    fn quantize_to_scale() {
+        let d_minor = Scale::new(2, Mode::Minor);
+        let quantized: Vec<u8> = (60..72)
+            .map(|pitch| u8::from(d_minor.quantize(Note::from_u8_lossy(pitch))))
+            .collect();
+        assert_eq!(
+            quantized,
+            vec![60, 60, 62, 62, 64, 65, 65, 67, 67, 69, 70, 70]
+        );
+
+        // a custom mask holding only the root and the fifth
+        let fifths = Scale::custom(0, 1 | 1 << 7);
+        assert_eq!(u8::from(fifths.quantize(Note::E4)), 67);
+        assert_eq!(u8::from(fifths.quantize(Note::Ab3)), 55);
+    }
+
+    #[test]
+    fn chords() {
+        let c_major = Scale::new(0, Mode::Major);
+        let seventh = Chord::new(4, Voicing::Close);
+        assert_eq!(
+            pitches(seventh.notes(&c_major, Note::D4)),
+            vec![62, 65, 69, 72]
+        );
+        let drop2 = Chord::new(4, Voicing::Drop2);
+        assert_eq!(
+            pitches(drop2.notes(&c_major, Note::C4)),
+            vec![55, 60, 64, 71]
+        );
+        let spread = Chord::new(3, Voicing::Spread);
+        assert_eq!(pitches(spread.notes(&c_major, Note::C4)), vec![60, 67, 76]);
+
+        let quantizer = PitchQuantizer::new(None, Some(Chord::new(3, Voicing::Close)));
+        let a_minor = Scale::new(9, Mode::Minor);
+        assert_eq!(
+            pitches(quantizer.notes(&a_minor, Note::A3)),
+            vec![57, 60, 64]
+        );
+    }
+}
@@ -1,9 +1,21 @@
-use float_extras::f64::modf;
-use wmidi::{Channel, MidiMessage, Note, U7};
+use crate::arpeggiator::Arpeggiator;
+use crate::euclid::Euclid;
+use crate::follow::FollowActions;
+use crate::groove::{Groove, Swing};
+use crate::humanize::Humanize;
+use crate::launch::LaunchQuantize;
+use crate::quantize::Quantize;
+use crate::recorder::{RecordMode, Recorder};
+use crate::scale::{Mode, PitchQuantizer, Scale};
+use crate::song::{Occurrence, Song};
+use crate::step::{Step, StepGrid};
+use crate::timing::TimingSource;
+use crate::transpose::{self, OutOfRange};
+use wmidi::{Channel, ControlFunction, MidiMessage, Note, U7};
 
 const SEQUENCE_COUNT: usize = 8;
 const MAX_EVENT_COUNT: usize = 2048;
-const PPQ: i32 = 96; // pulses per quarter note
+pub const PPQ: i64 = 96; // default pulses per quarter note
 const NOTE_ON: u8 = 0x90;
 const NOTE_OFF: u8 = 0x80;
 
@@ -11,24 +23,70 @@ pub struct SequencerConfig {
     tempo: f64,
     sample_rate: u64,
     buffer_size: f64,
+    ppq: i64,
 }
 
 impl SequencerConfig {
-    pub fn new(tempo: f64, sample_rate: u64, buffer_size: f64) -> Self {
+    pub fn new(tempo: f64, sample_rate: u64, buffer_size: f64, ppq: i64) -> Self {
         Self {
             tempo,
             sample_rate,
             buffer_size,
+            ppq,
         }
     }
 }
 
+/// A MIDI message at a fixed position in a sequence, in ticks (see `SequencerConfig::ppq`).
 #[derive(Clone, Debug)]
 pub struct SequencerEvent<'a> {
-    timestamp: f64,
+    tick: i64,
     message: MidiMessage<'a>,
 }
 
+/// A note with a start position and a length in ticks, expanded into a note on/off pair at
+/// render time.
+#[derive(Clone, Debug)]
+pub struct SequencerNote {
+    channel: Channel,
+    note: Note,
+    velocity: U7,
+    tick: i64,
+    length: i64,
+}
+
+impl SequencerNote {
+    pub fn new(channel: Channel, note: Note, velocity: U7, tick: i64, length: i64) -> Self {
+        Self {
+            channel,
+            note,
+            velocity,
+            tick,
+            length,
+        }
+    }
+
+    pub fn channel(&self) -> Channel {
+        self.channel
+    }
+
+    pub fn note(&self) -> Note {
+        self.note
+    }
+
+    pub fn tick(&self) -> i64 {
+        self.tick
+    }
+
+    pub fn length(&self) -> i64 {
+        self.length
+    }
+
+    pub fn velocity(&self) -> U7 {
+        self.velocity
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct MidiEvent<'a> {
     offset: f64,
@@ -37,296 +95,2314 @@ pub struct MidiEvent<'a> {
 
 impl<'a> MidiEvent<'a> {
 
+    /// A message sent `offset` samples after the start of the buffer.
+    pub fn new(offset: f64, message: MidiMessage<'a>) -> Self {
+        Self { offset, message }
+    }
+
     pub fn offset(&self) -> f64 {
         self.offset
     }
 
-    pub fn message(&self) -> &MidiMessage {
+    pub fn message(&self) -> &MidiMessage<'a> {
         &self.message
     }
 }
 
+/// The part of the timeline covered by the current buffer, or a part of it, in ticks.
+#[derive(Clone)]
+struct Window {
+    start: f64,
+    end: f64,
+    /// Start of the buffer, which offsets are relative to
+    origin: f64,
+    samples_per_tick: f64,
+    ppq: i64,
+    /// Whether fill mode is on, for trig conditions
+    fill: bool,
+    /// Key of the sequencer, for pitch quantizers without a scale of their own
+    key: Scale,
+    /// Transposition of all sequences in semitones
+    transpose: i32,
+    out_of_range: OutOfRange,
+    /// Index of the track being rendered, the seed of step grids without a seed of their own
+    track: usize,
+}
+
+impl Window {
+    fn contains(&self, tick: f64) -> bool {
+        tick >= self.start && tick < self.end
+    }
+
+    /// Offset in samples since the beginning of the buffer. Events that are already due (e.g. a
+    /// note-off that should have been sent in a previous buffer) are sent right away.
+    fn offset(&self, tick: f64) -> f64 {
+        (tick - self.origin).max(0.) * self.samples_per_tick
+    }
+
+    /// The part of the window between `start` and `end`.
+    fn segment(&self, start: f64, end: f64) -> Window {
+        Window {
+            start,
+            end,
+            ..self.clone()
+        }
+    }
+}
+
+/// A note that has been sent and still needs a note-off.
+#[derive(Clone, Debug)]
+struct ActiveNote {
+    channel: Channel,
+    note: Note,
+    /// Timeline position of the note-off, in ticks
+    off_tick: f64,
+    /// CC values to send after the note-off
+    restore: Vec<(ControlFunction, U7)>,
+}
+
+impl ActiveNote {
+    fn release<'a>(self, offset: f64, midi: &mut Vec<MidiEvent<'a>>) {
+        midi.push(MidiEvent {
+            offset,
+            message: MidiMessage::NoteOff(self.channel, self.note, U7::MIN),
+        });
+        for (control, value) in self.restore {
+            midi.push(MidiEvent {
+                offset,
+                message: MidiMessage::ControlChange(self.channel, control, value),
+            });
+        }
+    }
+}
+
+/// Something that starts in the current buffer.
+enum Trigger {
+    Event(usize),
+    /// A note with swing, groove and humanize applied, and the parameter locks to send before
+    /// and after it
+    Note {
+        note: SequencerNote,
+        locks: Vec<(ControlFunction, U7)>,
+        restore: Vec<(ControlFunction, U7)>,
+    },
+}
+
 #[derive(Clone)]
-struct MIDISequence<'a> {
-    length: f64,
+pub struct MIDISequence<'a> {
+    /// Loop length in ticks
+    length: i64,
+    /// Timeline position in ticks at which the first loop of the sequence starts. Sequences of
+    /// different lengths phase against each other relative to this point.
+    anchor: i64,
     events: Vec<SequencerEvent<'a>>,
+    notes: Vec<SequencerNote>,
+    steps: Option<StepGrid>,
+    swing: Option<Swing>,
+    groove: Option<Groove>,
+    humanize: Option<Humanize>,
+    quantize: Option<Quantize>,
+    pitch: Option<PitchQuantizer>,
+    /// Transposition in semitones and octaves, on top of the transposition of the sequencer
+    transpose: i32,
+    octave: i32,
+    arpeggiator: Option<Arpeggiator>,
+    /// What the track does after the sequence has played a number of times, when it is
+    /// launched as a clip
+    follow_actions: Option<FollowActions>,
+    active_notes: Vec<ActiveNote>,
+    /// Note-ons sent for events, as (channel, stored note, played note) for every voice, so that
+    /// the note-off releases the pitches that were played
+    event_notes: Vec<(Channel, Note, Note)>,
 }
 
 impl<'a> MIDISequence<'a> {
-    pub fn new(length: f64) -> MIDISequence<'a> {
+    pub fn new(length: i64) -> MIDISequence<'a> {
         MIDISequence {
             length,
+            anchor: 0,
             events: Vec::new(),
+            notes: Vec::new(),
+            steps: None,
+            swing: None,
+            groove: None,
+            humanize: None,
+            quantize: None,
+            pitch: None,
+            transpose: 0,
+            octave: 0,
+            arpeggiator: None,
+            follow_actions: None,
+            active_notes: Vec::new(),
+            event_notes: Vec::new(),
         }
     }
 
     pub fn add_event(&mut self, event: SequencerEvent<'a>) {
         self.events.push(event);
     }
-}
 
-pub struct Sequencer<'a> {
-    config: SequencerConfig,
-    sequences: Vec<MIDISequence<'a>>,
-}
+    /// A step sequence of `step_count` steps of `step_length` ticks each.
+    pub fn with_steps(channel: Channel, step_count: usize, step_length: i64) -> MIDISequence<'a> {
+        let mut sequence = Self::new(step_count as i64 * step_length);
+        sequence.steps = Some(StepGrid::new(channel, step_count, step_length));
+        sequence
+    }
 
-impl<'a> Sequencer<'a> {
-    pub fn new(config: SequencerConfig) -> Self {
-        let mut sequences = Vec::new();
-        let mut sequence = MIDISequence::new(1.);
+    pub fn add_note(&mut self, note: SequencerNote) {
+        self.notes.push(note);
+    }
 
-        let fr = 1.0 / 16.;
+    pub fn notes(&self) -> &[SequencerNote] {
+        &self.notes
+    }
 
-        let event1 = SequencerEvent {
-            timestamp: 0.,
-            message: MidiMessage::NoteOn(Channel::Ch1, Note::C4, U7::from_u8_lossy(100)),
-        };
-        sequence.add_event(event1);
+    /// Remove all notes. Notes that are already sounding are released as scheduled.
+    pub fn clear_notes(&mut self) {
+        self.notes.clear();
+    }
 
-        let event2 = SequencerEvent {
-            timestamp: fr,
-            message: MidiMessage::NoteOff(Channel::Ch1, Note::C4, U7::from_u8_lossy(0)),
-        };
-        sequence.add_event(event2);
+    /// Loop length in ticks
+    pub fn length(&self) -> i64 {
+        self.length
+    }
 
-        let event3 = SequencerEvent {
-            timestamp: fr * 2.,
-            message: MidiMessage::NoteOn(Channel::Ch1, Note::C4, U7::from_u8_lossy(100)),
-        };
-        sequence.add_event(event3);
+    /// The loop iteration playing at timeline position `tick`, and the position within it.
+    pub fn loop_position(&self, tick: f64) -> (i64, f64) {
+        let position = tick - self.anchor as f64;
+        let iteration = (position / self.length as f64).floor() as i64;
+        (iteration, Sequencer::mod_position(position, self.length))
+    }
 
-        let event4 = SequencerEvent {
-            timestamp: fr * 3.,
-            message: MidiMessage::NoteOff(Channel::Ch1, Note::C4, U7::from_u8_lossy(0)),
-        };
-        sequence.add_event(event4);
+    pub fn steps(&self) -> Option<&StepGrid> {
+        self.steps.as_ref()
+    }
 
-        let event5 = SequencerEvent {
-            timestamp: fr * 4.,
-            message: MidiMessage::NoteOn(Channel::Ch1, Note::C4, U7::from_u8_lossy(100)),
-        };
-        sequence.add_event(event5);
+    pub fn steps_mut(&mut self) -> Option<&mut StepGrid> {
+        self.steps.as_mut()
+    }
 
-        let event6 = SequencerEvent {
-            timestamp: fr * 5.,
-            message: MidiMessage::NoteOff(Channel::Ch1, Note::C4, U7::from_u8_lossy(0)),
-        };
-        sequence.add_event(event6);
+    pub fn set_step(&mut self, index: usize, step: Step) {
+        if let Some(steps) = &mut self.steps {
+            steps.set_step(index, step);
+        }
+    }
 
-        let event7 = SequencerEvent {
-            timestamp: fr * 6.,
-            message: MidiMessage::NoteOn(Channel::Ch1, Note::C4, U7::from_u8_lossy(100)),
-        };
-        sequence.add_event(event7);
+    pub fn clear_step(&mut self, index: usize) {
+        if let Some(steps) = &mut self.steps {
+            steps.clear_step(index);
+        }
+    }
 
-        let event8 = SequencerEvent {
-            timestamp: fr * 7.,
-            message: MidiMessage::NoteOff(Channel::Ch1, Note::C4, U7::from_u8_lossy(0)),
-        };
-        sequence.add_event(event8);
+    /// Restart the sequence from its first tick at timeline position `tick`.
+    pub fn reset(&mut self, tick: i64) {
+        self.anchor = tick;
+    }
 
-        let event9 = SequencerEvent {
-            timestamp: fr * 8.,
-            message: MidiMessage::NoteOn(Channel::Ch1, Note::C4, U7::from_u8_lossy(100)),
-        };
-        sequence.add_event(event9);
+    /// A step sequence holding a Euclidean rhythm.
+    pub fn euclidean(channel: Channel, step_length: i64, euclid: &Euclid) -> MIDISequence<'a> {
+        let mut sequence = Self::with_steps(channel, euclid.steps, step_length);
+        sequence.set_euclid(euclid, 0.);
+        sequence
+    }
 
-        let event10 = SequencerEvent {
-            timestamp: fr * 9.,
-            message: MidiMessage::NoteOff(Channel::Ch1, Note::C4, U7::from_u8_lossy(0)),
+    /// Regenerate the step grid from a Euclidean rhythm while the sequence is playing. When the
+    /// number of steps changes, the playhead at timeline position `tick` stays on the same step
+    /// (wrapped to the new length), and notes that are already sounding are released as
+    /// scheduled.
+    pub fn set_euclid(&mut self, euclid: &Euclid, tick: f64) {
+        let steps = match &mut self.steps {
+            Some(steps) => steps,
+            None => return,
         };
-        sequence.add_event(event10);
+        let step_length = steps.step_length();
+        if steps.len() != euclid.steps && euclid.steps > 0 {
+            let position = Sequencer::mod_position(tick - self.anchor as f64, self.length);
+            let step = (position / step_length as f64).floor() as i64;
+            let step_start = (tick - (position - (step * step_length) as f64)).round() as i64;
+            self.anchor = step_start - step.rem_euclid(euclid.steps as i64) * step_length;
+            steps.resize(euclid.steps);
+            self.length = steps.length();
+        }
+        euclid.fill(steps);
+    }
 
-        let event11 = SequencerEvent {
-            timestamp: fr * 10.,
-            message: MidiMessage::NoteOn(Channel::Ch1, Note::C4, U7::from_u8_lossy(100)),
-        };
-        sequence.add_event(event11);
+    pub fn set_swing(&mut self, swing: Option<Swing>) {
+        self.swing = swing;
+    }
 
-        let event12 = SequencerEvent {
-            timestamp: fr * 11.,
-            message: MidiMessage::NoteOff(Channel::Ch1, Note::C4, U7::from_u8_lossy(0)),
-        };
-        sequence.add_event(event12);
+    pub fn set_groove(&mut self, groove: Option<Groove>) {
+        self.groove = groove;
+    }
 
-        let event13 = SequencerEvent {
-            timestamp: fr * 12.,
-            message: MidiMessage::NoteOn(Channel::Ch1, Note::C4, U7::from_u8_lossy(100)),
-        };
-        sequence.add_event(event13);
+    pub fn set_humanize(&mut self, humanize: Option<Humanize>) {
+        self.humanize = humanize;
+    }
 
-        let event14 = SequencerEvent {
-            timestamp: fr * 13.,
-            message: MidiMessage::NoteOff(Channel::Ch1, Note::C4, U7::from_u8_lossy(0)),
-        };
-        sequence.add_event(event14);
+    /// Quantize the notes of the sequence at render time. The stored positions are never
+    /// changed.
+    pub fn set_quantize(&mut self, quantize: Option<Quantize>) {
+        self.quantize = quantize;
+    }
 
-        let event15 = SequencerEvent {
-            timestamp: fr * 14.,
-            message: MidiMessage::NoteOn(Channel::Ch1, Note::C4, U7::from_u8_lossy(100)),
-        };
-        sequence.add_event(event15);
+    /// Move the stored notes to their quantized positions.
+    pub fn quantize_notes(&mut self, quantize: &Quantize) {
+        for note in &mut self.notes {
+            let (offset, quantized) = quantize.note(note);
+            let mut tick = (note.tick as f64 + offset).round() as i64;
+            // notes moved past the end wrap around to the start of the loop
+            if self.length > 0 {
+                tick = tick.rem_euclid(self.length);
+            }
+            *note = SequencerNote { tick, ..quantized };
+        }
+    }
 
-        let event16 = SequencerEvent {
-            timestamp: fr * 15.,
-            message: MidiMessage::NoteOff(Channel::Ch1, Note::C4, U7::from_u8_lossy(0)),
-        };
-        sequence.add_event(event16);
-
-        //let event17 = SequencerEvent {
-            //timestamp: fr * 16.,
-            //message: MidiMessage::NoteOff(Channel::Ch1, Note::C4, U7::from_u8_lossy(0)),
-        //};
-        //sequence.add_event(event17);
-
-        sequences.push(sequence);
-
-        Self { config, sequences }
-    }
-
-    pub fn render_timeline(&self, now: u64, beat_position: f64, midi: &mut Vec<MidiEvent<'a>>) {
-        for sequence in &self.sequences {
-            let buffer_start_time = Self::mod_position(self, beat_position, sequence.length);
-            let loop_length = Self::beat_to_samples(self, sequence.length);
-            let buffer_end_time = buffer_start_time + self.config.buffer_size;
-
-            //println!("beat position: {}", beat_position);
-            //println!("buffer start:  {}", buffer_start_time);
-            //println!("buffer end:    {}", buffer_end_time);
-            //println!("---------------");
-            for event in &sequence.events {
-                // offset in samples since beginning of buffer
-                let event_offset = Self::beat_to_samples(self, event.timestamp);
-                // check whether the event should occur in the current buffer
-                let is_in_buffer =
-                    event_offset >= buffer_start_time && event_offset < buffer_end_time;
-                let loops_around = buffer_end_time > loop_length 
-                    && event_offset < buffer_end_time;
-                // TODO: add loop around logic
-
-                println!("buffer start  {}", buffer_start_time);
-                println!("timestamp     {}", event.timestamp);
-                println!("event time    {}", event_offset);
-                println!("loops around  {}", loops_around);
-                println!("-----------------------");
-
-                if is_in_buffer || loops_around {
-                    let mut offset = event_offset - buffer_start_time;
-                    if loops_around { offset +=  loop_length }
-                    let midi_event = MidiEvent {
-                        offset,
-                        message: event.message.clone(),
+    /// Snap the notes of the sequence to a scale at render time, optionally expanding them into
+    /// chords.
+    pub fn set_pitch_quantizer(&mut self, pitch: Option<PitchQuantizer>) {
+        self.pitch = pitch;
+    }
+
+    /// Transpose the sequence at render time. Notes that are sounding keep their pitch until
+    /// they are released.
+    pub fn set_transpose(&mut self, semitones: i32) {
+        self.transpose = semitones;
+    }
+
+    /// Shift by whole octaves, on top of `set_transpose`.
+    pub fn set_octave(&mut self, octaves: i32) {
+        self.octave = octaves;
+    }
+
+    /// The played pitch of `note`, or `None` if it is transposed out of range and dropped.
+    fn transposed(&self, window: &Window, note: Note) -> Option<Note> {
+        let semitones = self.transpose + self.octave * 12 + window.transpose;
+        transpose::transpose(note, semitones, window.out_of_range)
+    }
+
+    /// The pitches a stored note plays: transposed first, so that the pitch quantizer keeps
+    /// transposed notes in key, then snapped to the key and expanded into a chord. Empty when the
+    /// note is out of range.
+    fn pitches(&self, window: &Window, note: Note) -> Vec<Note> {
+        match (self.transposed(window, note), &self.pitch) {
+            (Some(played), Some(pitch)) => pitch.notes(&window.key, played),
+            (Some(played), None) => vec![played],
+            (None, _) => Vec::new(),
+        }
+    }
+
+    /// The messages to send for the event at `index`, with note messages played through
+    /// transposition and the pitch quantizer.
+    fn event_messages(&mut self, window: &Window, index: usize) -> Vec<MidiMessage<'a>> {
+        let message = self.events[index].message.clone();
+        match message {
+            MidiMessage::NoteOn(channel, note, velocity) if u8::from(velocity) > 0 => {
+                self.event_notes
+                    .retain(|(c, stored, _)| *c != channel || *stored != note);
+                let pitches = self.pitches(window, note);
+                for pitch in &pitches {
+                    self.event_notes.push((channel, note, *pitch));
+                }
+                pitches
+                    .into_iter()
+                    .map(|pitch| MidiMessage::NoteOn(channel, pitch, velocity))
+                    .collect()
+            }
+            MidiMessage::NoteOn(channel, note, velocity)
+            | MidiMessage::NoteOff(channel, note, velocity) => {
+                let mut played = Vec::new();
+                self.event_notes.retain(|(c, stored, pitch)| {
+                    let matches = *c == channel && *stored == note;
+                    if matches {
+                        played.push(*pitch);
+                    }
+                    !matches
+                });
+                // the note-on was sent before the sequence was started
+                if played.is_empty() {
+                    played = self.pitches(window, note);
+                }
+                played
+                    .into_iter()
+                    .map(|pitch| MidiMessage::NoteOff(channel, pitch, velocity))
+                    .collect()
+            }
+            message => vec![message],
+        }
+    }
+
+    /// Play the notes held on the MIDI input through an arpeggiator, on top of the notes of the
+    /// sequence.
+    pub fn set_arpeggiator(&mut self, arpeggiator: Option<Arpeggiator>) {
+        self.arpeggiator = arpeggiator;
+    }
+
+    pub fn arpeggiator_mut(&mut self) -> Option<&mut Arpeggiator> {
+        self.arpeggiator.as_mut()
+    }
+
+    pub fn set_follow_actions(&mut self, follow_actions: Option<FollowActions>) {
+        self.follow_actions = follow_actions;
+    }
+
+    /// Timing offset in ticks that swing and groove add to an event at `tick`. The stored
+    /// positions are never changed.
+    fn timing_offset(&self, tick: i64, ppq: i64) -> f64 {
+        let swing = self
+            .swing
+            .as_ref()
+            .map_or(0., |swing| swing.offset(tick, ppq));
+        let groove = self
+            .groove
+            .as_ref()
+            .and_then(|groove| groove.step(tick))
+            .map_or(0., |step| step.timing);
+        swing + groove
+    }
+
+    fn groove_velocity(&self, tick: i64, velocity: U7) -> U7 {
+        match self.groove.as_ref().and_then(|groove| groove.step(tick)) {
+            Some(step) => {
+                let velocity = (u8::from(velocity) as f64 * step.velocity).round();
+                U7::from_u8_lossy(velocity.clamp(1., 127.) as u8)
+            }
+            None => velocity,
+        }
+    }
+
+    /// Timeline position of a note played in loop `iteration`, with swing, groove and humanize
+    /// applied to it. `key` identifies the note for humanize.
+    fn place_note(
+        &self,
+        mut note: SequencerNote,
+        key: usize,
+        loop_start: f64,
+        iteration: i64,
+        ppq: i64,
+    ) -> (f64, SequencerNote) {
+        let mut tick = loop_start + note.tick as f64 + self.timing_offset(note.tick, ppq);
+        note.velocity = self.groove_velocity(note.tick, note.velocity);
+        if let Some(humanize) = &self.humanize {
+            tick += humanize.timing_offset(iteration, key);
+            note.velocity = humanize.velocity(iteration, key, note.velocity);
+        }
+        (tick, note)
+    }
+
+    /// Everything that starts between `start` and `end` on the timeline, in no particular order.
+    fn triggers(&self, window: &Window, start: f64, end: f64) -> Vec<(f64, Trigger)> {
+        let mut triggers = Vec::new();
+        let position = start - self.anchor as f64;
+        // start one loop early, swing, groove and humanize can move events across the loop
+        // boundary
+        let mut loop_start =
+            start - Sequencer::mod_position(position, self.length) - self.length as f64;
+        while loop_start < end + self.length as f64 {
+            let iteration = ((loop_start - self.anchor as f64) / self.length as f64).round() as i64;
+            // humanize leaves events alone: their note-ons and note-offs are separate events,
+            // which independent offsets could swap
+            for (index, event) in self.events.iter().enumerate() {
+                let tick =
+                    loop_start + event.tick as f64 + self.timing_offset(event.tick, window.ppq);
+                triggers.push((tick, Trigger::Event(index)));
+            }
+            for (index, note) in self.notes.iter().enumerate() {
+                let (offset, note) = match &self.quantize {
+                    Some(quantize) => quantize.note(note),
+                    None => (0., note.clone()),
+                };
+                let (tick, note) = self.place_note(note, index, loop_start, iteration, window.ppq);
+                let trigger = Trigger::Note {
+                    note,
+                    locks: Vec::new(),
+                    restore: Vec::new(),
+                };
+                triggers.push((tick + offset, trigger));
+            }
+            if let Some(steps) = &self.steps {
+                for step_note in steps.notes(iteration, window.fill, window.track as u64) {
+                    // retriggers of a ratchet are humanized together, so they stay evenly spaced
+                    let key = self.notes.len() + step_note.index;
+                    let (tick, note) =
+                        self.place_note(step_note.note, key, loop_start, iteration, window.ppq);
+                    let trigger = Trigger::Note {
+                        note,
+                        locks: step_note.locks,
+                        restore: step_note.restore,
                     };
-                    midi.push(midi_event);
+                    triggers.push((tick + step_note.offset, trigger));
+                }
+            }
+            loop_start += self.length as f64;
+        }
+        if let Some(arpeggiator) = &self.arpeggiator {
+            // arpeggiator steps follow the beat grid of the timeline, not the sequence loop
+            let rate = arpeggiator.rate() as f64;
+            let mut step = (start / rate).ceil() as i64;
+            while (step as f64 * rate) < end {
+                if let Some(note) = arpeggiator.note_at(step) {
+                    let trigger = Trigger::Note {
+                        note,
+                        locks: Vec::new(),
+                        restore: Vec::new(),
+                    };
+                    triggers.push((step as f64 * rate, trigger));
+                }
+                step += 1;
+            }
+        }
+        triggers.retain(|(tick, _)| *tick >= start && *tick < end);
+        triggers
+    }
 
+    fn render(&mut self, window: &Window, midi: &mut Vec<MidiEvent<'a>>) {
+        let mut triggers = self.triggers(window, window.start, window.end);
+        triggers.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        for (tick, trigger) in triggers {
+            // note-offs go out before anything that starts at the same tick
+            self.release_notes(window, tick, true, midi);
+
+            match trigger {
+                Trigger::Event(index) => {
+                    for message in self.event_messages(window, index) {
+                        midi.push(MidiEvent {
+                            offset: window.offset(tick),
+                            message,
+                        });
+                    }
                 }
+                Trigger::Note {
+                    note,
+                    locks,
+                    restore,
+                } => self.start_note(window, tick, note, locks, restore, midi),
             }
         }
+
+        self.release_notes(window, window.end, false, midi);
     }
 
-    fn beat_to_samples(&self, beat: f64) -> f64 {
-        beat / &self.config.tempo * 60. * &(self.config.sample_rate as f64)
+    /// Send a note that starts at `tick` and keep track of it until its note-off. A note that
+    /// started before the window, because it is chased, is sent at the start of the window.
+    fn start_note(
+        &mut self,
+        window: &Window,
+        tick: f64,
+        note: SequencerNote,
+        locks: Vec<(ControlFunction, U7)>,
+        restore: Vec<(ControlFunction, U7)>,
+        midi: &mut Vec<MidiEvent<'a>>,
+    ) {
+        let offset = window.offset(tick.max(window.start));
+        let pitches = self.pitches(window, note.note);
+        if pitches.is_empty() {
+            return;
+        }
+        // a retrigger of a pitch that is still sounding cuts off the previous note
+        for pitch in &pitches {
+            if let Some(position) = self
+                .active_notes
+                .iter()
+                .position(|active| active.channel == note.channel && active.note == *pitch)
+            {
+                self.active_notes.remove(position).release(offset, midi);
+            }
+        }
+        for (control, value) in locks {
+            midi.push(MidiEvent {
+                offset,
+                message: MidiMessage::ControlChange(note.channel, control, value),
+            });
+        }
+        // the locked CCs are restored once, with the note-off of the last voice
+        let last = pitches.len().saturating_sub(1);
+        for (voice, pitch) in pitches.into_iter().enumerate() {
+            midi.push(MidiEvent {
+                offset,
+                message: MidiMessage::NoteOn(note.channel, pitch, note.velocity),
+            });
+            self.active_notes.push(ActiveNote {
+                channel: note.channel,
+                note: pitch,
+                off_tick: tick + note.length as f64,
+                restore: if voice == last {
+                    restore.clone()
+                } else {
+                    Vec::new()
+                },
+            });
+        }
     }
 
-    fn mod_position(&self, beat: f64, length: f64) -> f64 {
-        let position_in_samples = Self::beat_to_samples(self, beat);
-        let length_in_samples = Self::beat_to_samples(self, length);
-        position_in_samples % length_in_samples
+    /// Send the notes that started before `tick` and are still sounding at it, and the last
+    /// value of each controller lane, so that long notes and automation come in when playback
+    /// joins in the middle of them.
+    fn chase(&mut self, window: &Window, tick: f64, midi: &mut Vec<MidiEvent<'a>>) {
+        let gates = self.steps.iter().flat_map(|steps| {
+            (0..steps.len()).filter_map(|index| steps.step(index).map(|step| step.gate))
+        });
+        let longest = self.notes.iter().map(|note| note.length).chain(gates).max();
+        let lookback = (longest.unwrap_or(0) + self.length) as f64;
+
+        let mut triggers = self.triggers(window, tick - lookback, tick);
+        triggers.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let mut lanes: Vec<(Channel, ControlFunction, U7)> = Vec::new();
+        let mut sounding = Vec::new();
+        for (start, trigger) in triggers {
+            match trigger {
+                Trigger::Event(index) => {
+                    if let MidiMessage::ControlChange(channel, control, value) =
+                        self.events[index].message
+                    {
+                        lanes.retain(|(c, f, _)| *c != channel || *f != control);
+                        lanes.push((channel, control, value));
+                    }
+                }
+                Trigger::Note { ref note, .. } if start + note.length as f64 > tick => {
+                    sounding.push((start, trigger));
+                }
+                Trigger::Note { .. } => {}
+            }
+        }
+
+        // controller values go out first, so that the chased notes start with them
+        let offset = window.offset(tick.max(window.start));
+        for (channel, control, value) in lanes {
+            midi.push(MidiEvent {
+                offset,
+                message: MidiMessage::ControlChange(channel, control, value),
+            });
+        }
+        for (start, trigger) in sounding {
+            if let Trigger::Note {
+                note,
+                locks,
+                restore,
+            } = trigger
+            {
+                self.start_note(window, start, note, locks, restore, midi);
+            }
+        }
     }
 
-    fn samples_per_beat(sample_rate: u64, tempo: f64) -> f64 {
-        sample_rate as f64 * 60. / tempo
+    /// Send note-offs for everything that is sounding, at the start of the window.
+    fn release_all(&mut self, window: &Window, midi: &mut Vec<MidiEvent<'a>>) {
+        let offset = window.offset(window.start);
+        for active in std::mem::take(&mut self.active_notes) {
+            active.release(offset, midi);
+        }
+        self.release_event_notes(window, midi);
     }
 
-    fn samples_per_subtick(sample_rate: u64, tempo: f64) -> f64 {
-        Self::samples_per_beat(sample_rate, tempo) / PPQ as f64
+    /// Send note-offs right away for the notes started by note events, e.g. when the sequence
+    /// stops playing and their own note-off events won't be rendered.
+    fn release_event_notes(&mut self, window: &Window, midi: &mut Vec<MidiEvent<'a>>) {
+        let offset = window.offset(window.start);
+        for (channel, _, played) in std::mem::take(&mut self.event_notes) {
+            midi.push(MidiEvent {
+                offset,
+                message: MidiMessage::NoteOff(channel, played, U7::MIN),
+            });
+        }
     }
 
-    fn subtick_position(beat_position: f64) -> i64 {
-        let (_, fractional) = modf(beat_position);
-        (PPQ as f64 * fractional).floor() as i64
+    /// Send note-offs for all active notes that end before `tick` (or at `tick`, if `inclusive`).
+    fn release_notes(
+        &mut self,
+        window: &Window,
+        tick: f64,
+        inclusive: bool,
+        midi: &mut Vec<MidiEvent<'a>>,
+    ) {
+        let mut released: Vec<ActiveNote> = Vec::new();
+        self.active_notes.retain(|active| {
+            let due = active.off_tick < tick || (inclusive && active.off_tick == tick);
+            if due {
+                released.push(active.clone());
+            }
+            !due
+        });
+        released.sort_by(|a, b| a.off_tick.total_cmp(&b.off_tick));
+
+        for active in released {
+            let offset = window.offset(active.off_tick);
+            active.release(offset, midi);
+        }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// A clip launch waiting for its quantization boundary.
+#[derive(Clone, Debug)]
+struct QueuedLaunch {
+    /// Slot to play, `None` stops the track
+    slot: Option<usize>,
+    /// Timeline position at which the launch takes effect, in ticks
+    tick: i64,
+}
 
-    #[test]
-    fn beat_to_samples_zero() {
-        let config = SequencerConfig {
-            tempo: 120.,
-            sample_rate: 44100,
-            buffer_size: 512,
-        };
-        let sequencer = Sequencer::new(config);
-        let result = sequencer.beat_to_samples(0.);
-        assert_eq!(result, 0.);
+/// A lane of the arrangement with slots for patterns (clips), of which one plays at a time.
+#[derive(Clone)]
+pub struct Track<'a> {
+    slots: Vec<Option<MIDISequence<'a>>>,
+    /// Slot that is playing
+    playing: Option<usize>,
+    queued: Option<QueuedLaunch>,
+    muted: bool,
+    solo: bool,
+    /// A disabled track is silent, even when it is soloed
+    enabled: bool,
+    /// Chase notes that are already sounding when the track becomes audible again
+    chase: bool,
+    /// Whether the track was heard in the previous buffer
+    audible: bool,
+}
+
+impl<'a> Track<'a> {
+    pub fn new(slot_count: usize) -> Self {
+        Self {
+            slots: vec![None; slot_count],
+            playing: None,
+            queued: None,
+            muted: false,
+            solo: false,
+            enabled: true,
+            chase: false,
+            audible: true,
+        }
     }
 
-    #[test]
-    fn beat_to_samples_one() {
-        let config = SequencerConfig {
-            tempo: 120.,
-            sample_rate: 44100,
-            buffer_size: 512,
-        };
-        let sequencer = Sequencer::new(config);
-        let result = sequencer.beat_to_samples(1.);
-        assert_eq!(result, 22050.);
+    /// A track that plays `sequence` from its first slot.
+    pub fn with_sequence(sequence: MIDISequence<'a>) -> Self {
+        Self {
+            slots: vec![Some(sequence)],
+            playing: Some(0),
+            ..Self::new(1)
+        }
     }
 
-    #[test]
-    fn mod_position_zero() {
-        let config = SequencerConfig {
-            tempo: 120.,
-            sample_rate: 44100,
-            buffer_size: 1024,
-        };
-        let sequencer = Sequencer::new(config);
-        let result = sequencer.mod_position(0., 1.);
-        assert_eq!(result, 0.);
+    pub fn set_slot(&mut self, slot: usize, sequence: MIDISequence<'a>) {
+        if slot >= self.slots.len() {
+            self.slots.resize(slot + 1, None);
+        }
+        self.slots[slot] = Some(sequence);
     }
 
-    #[test]
-    fn mod_position_one() {
-        let config = SequencerConfig {
-            tempo: 120.,
-            sample_rate: 44100,
-            buffer_size: 1024,
-        };
-        let sequencer = Sequencer::new(config);
-        let result = sequencer.mod_position(0., 1.);
-        assert_eq!(result, 0.);
+    pub fn slot(&self, slot: usize) -> Option<&MIDISequence<'a>> {
+        self.slots.get(slot).and_then(|sequence| sequence.as_ref())
     }
 
-    #[test]
-    fn mod_position_longer() {
-        let config = SequencerConfig {
-            tempo: 120.,
-            sample_rate: 44100,
-            buffer_size: 1024,
-        };
-        let sequencer = Sequencer::new(config);
-        let result = sequencer.mod_position(1., 2.);
-        assert_eq!(result, 22050.);
+    pub fn slot_mut(&mut self, slot: usize) -> Option<&mut MIDISequence<'a>> {
+        self.slots
+            .get_mut(slot)
+            .and_then(|sequence| sequence.as_mut())
     }
 
-    #[test]
-    fn samples_per_beat_test() {
-        let result = Sequencer::samples_per_beat(44100, 120.);
-        assert_eq!(result, 22050.);
+    pub fn playing(&self) -> Option<usize> {
+        self.playing
     }
 
-    #[test]
-    fn samples_per_subtick_test() {
-        let result = Sequencer::samples_per_subtick(44100, 120.);
-        assert_eq!(result, 229.6875);
+    /// The sequence that is playing.
+    pub fn sequence(&self) -> Option<&MIDISequence<'a>> {
+        self.slot(self.playing?)
     }
 
-    #[test]
-    fn subtick_position_test() {
-        let result = Sequencer::subtick_position(0.5);
-        assert_eq!(result, 48);
+    pub fn sequence_mut(&mut self) -> Option<&mut MIDISequence<'a>> {
+        self.slot_mut(self.playing?)
+    }
+
+    fn sequences_mut(&mut self) -> impl Iterator<Item = &mut MIDISequence<'a>> {
+        self.slots.iter_mut().flatten()
+    }
+
+    /// Playback starts or jumps to the start of the window. Notes that were sounding are
+    /// released, and with `chase` the notes and controller values at the new position are sent.
+    fn locate(&mut self, window: &Window, chase: bool, midi: &mut Vec<MidiEvent<'a>>) {
+        for sequence in self.sequences_mut() {
+            sequence.release_all(window, midi);
+        }
+        if chase {
+            if let Some(sequence) = self.sequence_mut() {
+                sequence.chase(window, window.start, midi);
+            }
+            // the track is heard from here on, it must not be chased again on the next render
+            self.audible = true;
+        }
+    }
+
+    /// Play the sequence in `slot`, starting from its first tick at timeline position `tick`.
+    /// Playing an empty slot stops the track. If the sequence has follow actions, the next
+    /// launch is queued right away.
+    pub fn play(&mut self, slot: usize, tick: i64) {
+        match self.slot_mut(slot) {
+            Some(sequence) => {
+                sequence.reset(tick);
+                self.playing = Some(slot);
+                self.queued = self.follow(slot, tick);
+            }
+            None => self.stop(),
+        }
+    }
+
+    /// The launch the follow actions of the sequence in `slot` lead to, when it starts at
+    /// `tick`.
+    fn follow(&self, slot: usize, tick: i64) -> Option<QueuedLaunch> {
+        let sequence = self.slot(slot)?;
+        let follow_actions = sequence.follow_actions.as_ref()?;
+        let at = tick + follow_actions.after() as i64 * sequence.length;
+        let action = follow_actions.choose(at)?;
+        let occupied: Vec<bool> = self.slots.iter().map(|slot| slot.is_some()).collect();
+        Some(QueuedLaunch {
+            slot: follow_actions.target(action, slot, &occupied, at),
+            tick: at,
+        })
+    }
+
+    /// Stop playing. Notes that are sounding are released as scheduled.
+    pub fn stop(&mut self) {
+        self.playing = None;
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    /// While any enabled track is soloed, only soloed tracks are heard.
+    pub fn set_solo(&mut self, solo: bool) {
+        self.solo = solo;
+    }
+
+    pub fn is_solo(&self) -> bool {
+        self.solo
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Whether notes that started while the track was silent are chased when it is heard again.
+    /// Otherwise only notes that start after that are played.
+    pub fn set_chase(&mut self, chase: bool) {
+        self.chase = chase;
+    }
+
+    /// Whether the track is heard, given that some track is soloed or not.
+    fn is_audible(&self, solo: bool) -> bool {
+        self.enabled && !self.muted && (self.solo || !solo)
+    }
+
+    /// The slot that was launched and waits for its boundary, `Some(None)` for a queued stop.
+    pub fn queued(&self) -> Option<Option<usize>> {
+        self.queued.as_ref().map(|launch| launch.slot)
+    }
+
+    /// Play (or stop, with `None`) at timeline position `tick`, replacing any launch that is
+    /// still waiting.
+    fn queue(&mut self, slot: Option<usize>, tick: i64) {
+        self.queued = Some(QueuedLaunch { slot, tick });
+    }
+
+    /// Carry out the queued launch if it is due at `tick`. Returns the position of a launch
+    /// that is still waiting.
+    fn launch_due(&mut self, tick: f64) -> Option<f64> {
+        let launch = self.queued.take()?;
+        if launch.tick as f64 > tick {
+            let waiting = launch.tick as f64;
+            self.queued = Some(launch);
+            return Some(waiting);
+        }
+        match launch.slot {
+            Some(slot) => self.play(slot, launch.tick),
+            None => self.stop(),
+        }
+        None
+    }
+
+    fn render(&mut self, window: &Window, audible: bool, midi: &mut Vec<MidiEvent<'a>>) {
+        if !audible {
+            // silencing a track releases its notes right away
+            if self.audible {
+                for sequence in self.sequences_mut() {
+                    sequence.release_all(window, midi);
+                }
+            }
+            self.audible = false;
+            return;
+        }
+        if !self.audible && self.chase {
+            if let Some(sequence) = self.sequence_mut() {
+                sequence.chase(window, window.start, midi);
+            }
+        }
+        self.audible = true;
+
+        let playing = self.playing;
+        for (slot, sequence) in self.slots.iter_mut().enumerate() {
+            match sequence {
+                Some(sequence) if Some(slot) == playing => sequence.render(window, midi),
+                // sequences that were switched away from still release their notes, notes of
+                // note events have no end of their own and are released right away
+                Some(sequence) => {
+                    sequence.release_notes(window, window.end, false, midi);
+                    sequence.release_event_notes(window, midi);
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+pub struct Sequencer<'a> {
+    config: SequencerConfig,
+    tracks: Vec<Track<'a>>,
+    /// Arrangement that decides which pattern each track plays
+    song: Option<Song>,
+    /// The occurrence of a song section the tracks were last switched to, `None` until the
+    /// song is first followed
+    song_occurrence: Option<Option<Occurrence>>,
+    launch_quantize: LaunchQuantize,
+    /// Length of a bar in beats, shared with the Link session
+    quantum: f64,
+    fill: bool,
+    key: Scale,
+    transpose: i32,
+    octave: i32,
+    out_of_range: OutOfRange,
+    recorder: Recorder,
+    /// Index of the track whose playing sequence the MIDI input is recorded into
+    armed: Option<usize>,
+    /// Chase notes and controller values when playback starts or jumps
+    chase: bool,
+    /// End of the last rendered window, to tell a start or a jump from continuous playback
+    last_end: Option<f64>,
+    /// The slot each track played when the transport stopped, played again when it starts
+    stopped_slots: Option<Vec<Option<usize>>>,
+}
+
+impl<'a> Sequencer<'a> {
+    pub fn new(config: SequencerConfig) -> Self {
+        let mut tracks = Vec::new();
+
+        // a C4 on every other 64th note of a beat, placed at full precision so that it stays on
+        // the grid at any PPQ
+        let fr = |index: i64| config.ppq * index / 16;
+        let mut sequence = MIDISequence::new(config.ppq);
+        for index in (0..16).step_by(2) {
+            let length = (fr(index + 1) - fr(index)).max(1);
+            sequence.add_note(SequencerNote::new(
+                Channel::Ch1,
+                Note::C4,
+                U7::from_u8_lossy(100),
+                fr(index),
+                length,
+            ));
+        }
+
+        tracks.push(Track::with_sequence(sequence));
+
+        Self {
+            config,
+            tracks,
+            song: None,
+            song_occurrence: None,
+            launch_quantize: LaunchQuantize::Bar,
+            quantum: 4.,
+            fill: false,
+            key: Scale::new(0, Mode::Major),
+            transpose: 0,
+            octave: 0,
+            out_of_range: OutOfRange::Drop,
+            recorder: Recorder::new(RecordMode::Overdub),
+            armed: None,
+            chase: false,
+            last_end: None,
+            stopped_slots: None,
+        }
+    }
+
+    /// Fill mode, for steps with a fill or not-fill trig condition
+    pub fn set_fill(&mut self, fill: bool) {
+        self.fill = fill;
+    }
+
+    /// The key that pitch quantizers snap to, unless they have a scale of their own. Changing
+    /// it retunes all sequences from the next note on.
+    pub fn set_key(&mut self, key: Scale) {
+        self.key = key;
+    }
+
+    /// Transpose all sequences, on top of their own transposition.
+    pub fn set_transpose(&mut self, semitones: i32) {
+        self.transpose = semitones;
+    }
+
+    pub fn set_octave(&mut self, octaves: i32) {
+        self.octave = octaves;
+    }
+
+    /// Whether notes transposed out of the MIDI range are clamped or dropped
+    pub fn set_out_of_range(&mut self, policy: OutOfRange) {
+        self.out_of_range = policy;
+    }
+
+    /// When playback starts or jumps into the middle of a pattern, send the notes that should
+    /// already be sounding and the last value of each controller lane.
+    pub fn set_chase(&mut self, chase: bool) {
+        self.chase = chase;
+    }
+
+    /// Regenerate the Euclidean sequence playing on a track from new parameters, without
+    /// moving its playhead.
+    pub fn set_euclid(&mut self, index: usize, euclid: &Euclid, beat_position: f64) {
+        let tick = Self::subtick_position(beat_position, self.config.ppq);
+        if let Some(sequence) = self.sequence_mut(index) {
+            sequence.set_euclid(euclid, tick);
+        }
+    }
+
+    /// Arm a track for recording, or disarm with `None`. Arming starts a new take.
+    pub fn arm(&mut self, index: Option<usize>) {
+        self.armed = index.filter(|index| *index < self.tracks.len());
+        self.recorder.start();
+    }
+
+    pub fn recorder_mut(&mut self) -> &mut Recorder {
+        &mut self.recorder
+    }
+
+    /// Handle a message received on the MIDI input. `beat_position` is the timeline position
+    /// the message was played at, with input latency already compensated.
+    pub fn midi_input(&mut self, message: &MidiMessage, beat_position: f64) {
+        let armed = self.armed.and_then(|index| self.tracks.get_mut(index));
+        if let Some(sequence) = armed.and_then(|track| track.sequence_mut()) {
+            let tick = Self::subtick_position(beat_position, self.config.ppq);
+            match message {
+                MidiMessage::NoteOn(channel, note, velocity) if u8::from(*velocity) > 0 => self
+                    .recorder
+                    .note_on(sequence, *channel, *note, *velocity, tick),
+                MidiMessage::NoteOn(channel, note, _) | MidiMessage::NoteOff(channel, note, _) => {
+                    self.recorder.note_off(sequence, *channel, *note, tick)
+                }
+                _ => {}
+            }
+        }
+
+        let sequences = self
+            .tracks
+            .iter_mut()
+            .flat_map(|track| track.sequences_mut());
+        for sequence in sequences {
+            if let Some(arpeggiator) = &mut sequence.arpeggiator {
+                match message {
+                    MidiMessage::NoteOn(_, note, velocity) if u8::from(*velocity) > 0 => {
+                        arpeggiator.note_on(*note, *velocity)
+                    }
+                    MidiMessage::NoteOn(_, note, _) | MidiMessage::NoteOff(_, note, _) => {
+                        arpeggiator.note_off(*note)
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    pub fn add_track(&mut self, track: Track<'a>) -> usize {
+        self.tracks.push(track);
+        self.tracks.len() - 1
+    }
+
+    /// Add a track that plays `sequence`.
+    pub fn add_sequence(&mut self, sequence: MIDISequence<'a>) -> usize {
+        self.add_track(Track::with_sequence(sequence))
+    }
+
+    pub fn track_mut(&mut self, index: usize) -> Option<&mut Track<'a>> {
+        self.tracks.get_mut(index)
+    }
+
+    /// The sequence playing on a track.
+    pub fn sequence_mut(&mut self, index: usize) -> Option<&mut MIDISequence<'a>> {
+        self.tracks.get_mut(index)?.sequence_mut()
+    }
+
+    /// Play an arrangement, or stop following it with `None`. While a song is set, it decides
+    /// which pattern slot every track plays.
+    pub fn set_song(&mut self, song: Option<Song>) {
+        self.song = song;
+        self.song_occurrence = None;
+    }
+
+    pub fn set_launch_quantize(&mut self, launch_quantize: LaunchQuantize) {
+        self.launch_quantize = launch_quantize;
+    }
+
+    /// The quantum of the Link session, which launches are quantized to.
+    pub fn set_quantum(&mut self, quantum: f64) {
+        self.quantum = quantum;
+    }
+
+    /// The timeline position in ticks at which a launch at `beat_position` takes effect.
+    fn launch_tick(&self, beat_position: f64) -> i64 {
+        let boundary = self.launch_quantize.boundary(beat_position, self.quantum);
+        Self::subtick_position(boundary, self.config.ppq).round() as i64
+    }
+
+    /// Queue the clip in `slot` of a track to start at the next launch boundary. The clip plays
+    /// from its beginning, lined up with the boundary.
+    pub fn launch_clip(&mut self, track: usize, slot: usize, beat_position: f64) {
+        let tick = self.launch_tick(beat_position);
+        if let Some(track) = self.tracks.get_mut(track) {
+            track.queue(Some(slot), tick);
+        }
+    }
+
+    /// Queue a track to stop at the next launch boundary.
+    pub fn stop_clip(&mut self, track: usize, beat_position: f64) {
+        let tick = self.launch_tick(beat_position);
+        if let Some(track) = self.tracks.get_mut(track) {
+            track.queue(None, tick);
+        }
+    }
+
+    /// Queue the clips in row `scene` of every track to start together at the next launch
+    /// boundary. Tracks with an empty slot in the row stop.
+    pub fn launch_scene(&mut self, scene: usize, beat_position: f64) {
+        let tick = self.launch_tick(beat_position);
+        for track in &mut self.tracks {
+            let slot = track.slot(scene).map(|_| scene);
+            track.queue(slot, tick);
+        }
+    }
+
+    /// Start every track at the next `quantize` boundary, e.g. when a peer of the Link session
+    /// starts playback. Tracks play the clip they played when the transport stopped, from its
+    /// beginning.
+    pub fn start(&mut self, beat_position: f64, quantize: LaunchQuantize) {
+        let boundary = quantize.boundary(beat_position, self.quantum);
+        let tick = Self::subtick_position(boundary, self.config.ppq).round() as i64;
+        let slots = self.stopped_slots.take();
+        for (index, track) in self.tracks.iter_mut().enumerate() {
+            let slot = match &slots {
+                Some(slots) => slots.get(index).copied().flatten(),
+                None => track.playing(),
+            };
+            if let Some(slot) = slot {
+                track.queue(Some(slot), tick);
+            }
+        }
+    }
+
+    /// Stop every track at the next `quantize` boundary.
+    pub fn stop(&mut self, beat_position: f64, quantize: LaunchQuantize) {
+        let boundary = quantize.boundary(beat_position, self.quantum);
+        let tick = Self::subtick_position(boundary, self.config.ppq).round() as i64;
+        // a clip that waits for its launch is what the track would have played
+        let slots = self
+            .tracks
+            .iter()
+            .map(|track| track.queued().unwrap_or(track.playing()))
+            .collect();
+        self.stopped_slots = Some(slots);
+        for track in &mut self.tracks {
+            track.queue(None, tick);
+        }
+    }
+
+    /// Restart the sequence playing on a track at the most recent quantum boundary, so that it
+    /// lines up with the bars of the Link session again.
+    pub fn resync_to_quantum(&mut self, index: usize, beat_position: f64, quantum: f64) {
+        let boundary = (beat_position / quantum).floor() * quantum;
+        let tick = Self::subtick_position(boundary, self.config.ppq).round() as i64;
+        if let Some(sequence) = self.sequence_mut(index) {
+            sequence.reset(tick);
+        }
+    }
+
+    /// Switch every track to the pattern the song plays at `tick` when a new section starts,
+    /// restarting the patterns at the first bar of the section. Clips launched or stopped
+    /// during a section keep playing until the next one. Returns the timeline position of the
+    /// next bar line.
+    fn follow_song(&mut self, tick: f64) -> Option<f64> {
+        let song = self.song.as_ref()?;
+        let ppq = self.config.ppq;
+        let bar = song.bar_at(tick / ppq as f64);
+        let next_bar = Some(Self::subtick_position(song.bar_start(bar + 1), ppq));
+        let occurrence = song.occurrence_at(bar);
+        if self.song_occurrence.as_ref() == Some(&occurrence) {
+            return next_bar;
+        }
+        for (index, track) in self.tracks.iter_mut().enumerate() {
+            let slot = occurrence.as_ref().and_then(|occurrence| {
+                let section = song.section(occurrence.section)?;
+                let start = Self::subtick_position(song.bar_start(occurrence.bar), ppq);
+                Some((section.patterns.get(index).copied().flatten()?, start))
+            });
+            match slot {
+                Some((slot, start)) => track.play(slot, start.round() as i64),
+                None => track.stop(),
+            }
+        }
+        self.song_occurrence = Some(occurrence);
+        next_bar
+    }
+
+    pub fn set_tempo(&mut self, tempo: f64) {
+        self.config.tempo = tempo;
+    }
+
+    /// Sample rate and buffer size of the audio callback, which can change between callbacks.
+    pub fn set_audio_config(&mut self, sample_rate: u64, buffer_size: f64) {
+        self.config.sample_rate = sample_rate;
+        self.config.buffer_size = buffer_size;
+    }
+
+    /// Renders the buffer that starts at host time `time` in microseconds, on the timeline and
+    /// at the tempo of `source`.
+    pub fn render_source(
+        &mut self,
+        source: &dyn TimingSource,
+        time: i64,
+        midi: &mut Vec<MidiEvent<'a>>,
+    ) {
+        self.set_tempo(source.tempo());
+        let beat_position = source.beat_at_time(time, self.quantum);
+        self.render_timeline(beat_position, midi);
+    }
+
+    pub fn render_timeline(&mut self, beat_position: f64, midi: &mut Vec<MidiEvent<'a>>) {
+        let samples_per_tick = self.ticks_to_samples(1.);
+        // the render window is computed in ticks, only the final offsets are converted to samples
+        let start = Self::subtick_position(beat_position, self.config.ppq);
+        let end = start + self.config.buffer_size / samples_per_tick;
+
+        // a buffer that starts within a buffer length of where the last one ended continues
+        // from there, so that jitter of the timing source neither plays an event twice nor
+        // drops one. Anything further away is a start or a jump.
+        let (window_start, mut located) = match self.last_end {
+            Some(last_end) if (start - last_end).abs() <= end - start => (last_end, false),
+            _ => (start, true),
+        };
+        self.last_end = Some(end);
+        let window = self.window(window_start, end, start);
+
+        // the window is split at bar lines and launch boundaries, so that song sections and
+        // clips change exactly on the boundary
+        let mut segment_start = window.start;
+        while segment_start < window.end {
+            // launches that become due in the buffer start a new segment as well
+            let next_launch = self
+                .tracks
+                .iter_mut()
+                .filter_map(|track| track.launch_due(segment_start))
+                .fold(window.end, f64::min);
+            let segment_end = match self.follow_song(segment_start) {
+                Some(next_bar) => next_bar.min(next_launch),
+                None => next_launch,
+            };
+            let mut segment = window.segment(segment_start, segment_end);
+            let solo = self.tracks.iter().any(|track| track.enabled && track.solo);
+            for (index, track) in self.tracks.iter_mut().enumerate() {
+                segment.track = index;
+                let audible = track.is_audible(solo);
+                if located {
+                    track.locate(&segment, self.chase && audible, midi);
+                }
+                track.render(&segment, audible, midi);
+            }
+            located = false;
+            segment_start = segment_end;
+        }
+
+        // merge the output of all tracks, keeping the order of events at the same offset
+        midi.sort_by(|a, b| a.offset.total_cmp(&b.offset));
+    }
+
+    /// The timing source stopped: send note-offs for every note that is sounding, at the start
+    /// of the buffer. Rendering again afterwards is a start.
+    pub fn release_all(&mut self, midi: &mut Vec<MidiEvent<'a>>) {
+        let tick = self.last_end.take().unwrap_or(0.);
+        let window = self.window(tick, tick, tick);
+        for track in &mut self.tracks {
+            for sequence in track.sequences_mut() {
+                sequence.release_all(&window, midi);
+            }
+        }
+    }
+
+    /// The render window from `start` to `end` in ticks, with sample offsets counted from
+    /// `origin`.
+    fn window(&self, start: f64, end: f64, origin: f64) -> Window {
+        Window {
+            start,
+            end,
+            origin,
+            samples_per_tick: self.ticks_to_samples(1.),
+            ppq: self.config.ppq,
+            fill: self.fill,
+            key: self.key,
+            transpose: self.transpose + self.octave * 12,
+            out_of_range: self.out_of_range,
+            track: 0,
+        }
+    }
+
+    fn ticks_to_samples(&self, ticks: f64) -> f64 {
+        ticks
+            * Self::samples_per_subtick(self.config.sample_rate, self.config.tempo, self.config.ppq)
+    }
+
+    fn mod_position(tick: f64, length: i64) -> f64 {
+        tick.rem_euclid(length as f64)
+    }
+
+    fn samples_per_beat(sample_rate: u64, tempo: f64) -> f64 {
+        sample_rate as f64 * 60. / tempo
+    }
+
+    fn samples_per_subtick(sample_rate: u64, tempo: f64, ppq: i64) -> f64 {
+        Self::samples_per_beat(sample_rate, tempo) / ppq as f64
+    }
+
+    fn subtick_position(beat_position: f64, ppq: i64) -> f64 {
+        beat_position * ppq as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arpeggiator::ArpMode;
+    use crate::euclid::Euclid;
+    use crate::follow::FollowAction;
+    use crate::groove::{GrooveStep, SwingGrid};
+    use crate::midi_clock::ClockInput;
+    use crate::mtc::{FrameRate, MtcInput, MtcOutput};
+    use crate::scale::{Chord, Voicing};
+    use crate::song::{EndAction, Section};
+    use crate::step::TrigCondition;
+    use crate::timing::{FakeTiming, InternalClock};
+
+    fn config(buffer_size: f64) -> SequencerConfig {
+        SequencerConfig {
+            tempo: 120.,
+            sample_rate: 44100,
+            buffer_size,
+            ppq: PPQ,
+        }
+    }
+
+    fn sequencer_with_tracks(tracks: Vec<Track<'static>>) -> Sequencer<'static> {
+        let mut sequencer = Sequencer::new(config(512.));
+        sequencer.tracks = tracks;
+        sequencer
+    }
+
+    fn sequencer_with_notes(notes: Vec<SequencerNote>) -> Sequencer<'static> {
+        let mut sequence = MIDISequence::new(PPQ);
+        for note in notes {
+            sequence.add_note(note);
+        }
+        sequencer_with_tracks(vec![Track::with_sequence(sequence)])
+    }
+
+    /// A track with a clip for each pitch, playing the pitch at the start of a `length` loop.
+    fn track_with_clips(pitches: &[Note], length: i64) -> Track<'static> {
+        let mut track = Track::new(pitches.len());
+        for (slot, pitch) in pitches.iter().enumerate() {
+            let mut sequence = MIDISequence::new(length);
+            sequence.add_note(note(*pitch, 0, 12));
+            track.set_slot(slot, sequence);
+        }
+        track
+    }
+
+    fn note(note: Note, tick: i64, length: i64) -> SequencerNote {
+        SequencerNote::new(Channel::Ch1, note, U7::from_u8_lossy(100), tick, length)
+    }
+
+    /// Render the first `beats` of the timeline buffer by buffer, and return every event with
+    /// its position in ticks.
+    fn render_ticks(
+        sequencer: &mut Sequencer<'static>,
+        beats: f64,
+    ) -> Vec<(f64, MidiMessage<'static>)> {
+        let samples_per_tick = sequencer.ticks_to_samples(1.);
+        let buffer_ticks = sequencer.config.buffer_size / samples_per_tick;
+        let mut rendered = Vec::new();
+        let mut tick = 0.;
+        while tick < beats * PPQ as f64 {
+            let mut midi = Vec::new();
+            sequencer.render_timeline(tick / PPQ as f64, &mut midi);
+            for event in midi {
+                rendered.push((
+                    tick + event.offset() / samples_per_tick,
+                    event.message().clone(),
+                ));
+            }
+            tick += buffer_ticks;
+        }
+        rendered.retain(|(tick, _)| *tick < beats * PPQ as f64);
+        rendered
+    }
+
+    /// Render the buffer that starts at `beat`, and return every event with its offset.
+    fn render_buffer(
+        sequencer: &mut Sequencer<'static>,
+        beat: f64,
+    ) -> Vec<(f64, MidiMessage<'static>)> {
+        let mut midi = Vec::new();
+        sequencer.render_timeline(beat, &mut midi);
+        midi.into_iter()
+            .map(|event| (event.offset(), event.message().clone()))
+            .collect()
+    }
+
+    /// The messages of the buffer that starts at `beat`.
+    fn messages(sequencer: &mut Sequencer<'static>, beat: f64) -> Vec<MidiMessage<'static>> {
+        render_buffer(sequencer, beat)
+            .into_iter()
+            .map(|(_, message)| message)
+            .collect()
+    }
+
+    /// The note-offs sent when the timing source stops.
+    fn released(sequencer: &mut Sequencer<'static>) -> Vec<(f64, MidiMessage<'static>)> {
+        let mut midi = Vec::new();
+        sequencer.release_all(&mut midi);
+        midi.into_iter()
+            .map(|event| (event.offset(), event.message().clone()))
+            .collect()
+    }
+
+    #[test]
+    fn default_pattern_at_any_ppq() {
+        let mut sequencer = Sequencer::new(SequencerConfig::new(120., 44100, 512., 24));
+        let notes = sequencer.sequence_mut(0).unwrap().notes();
+        let ticks: Vec<i64> = notes.iter().map(|note| note.tick()).collect();
+        assert_eq!(ticks, vec![0, 3, 6, 9, 12, 15, 18, 21]);
+        assert!(notes.iter().all(|note| note.length() == 1));
+    }
+
+    #[test]
+    fn ticks_to_samples_zero() {
+        let sequencer = Sequencer::new(config(512.));
+        let result = sequencer.ticks_to_samples(0.);
+        assert_eq!(result, 0.);
+    }
+
+    #[test]
+    fn ticks_to_samples_one_beat() {
+        let sequencer = Sequencer::new(config(512.));
+        let result = sequencer.ticks_to_samples(PPQ as f64);
+        assert_eq!(result, 22050.);
+    }
+
+    #[test]
+    fn audio_config_sets_window_length() {
+        let mut sequencer = sequencer_with_notes(vec![note(Note::C4, 0, 6), note(Note::D4, 8, 10)]);
+        sequencer.set_audio_config(48000, 4000.);
+        assert_eq!(sequencer.ticks_to_samples(PPQ as f64), 24000.);
+
+        // 4000 samples at 48 kHz and 120 bpm are 16 ticks
+        let mut midi = Vec::new();
+        sequencer.render_timeline(0., &mut midi);
+        assert_eq!(midi.len(), 3);
+        assert_eq!(midi[2].offset(), 2000.);
+    }
+
+    #[test]
+    fn mod_position_zero() {
+        let result = Sequencer::mod_position(0., PPQ);
+        assert_eq!(result, 0.);
+    }
+
+    #[test]
+    fn mod_position_one() {
+        let result = Sequencer::mod_position(PPQ as f64, PPQ);
+        assert_eq!(result, 0.);
+    }
+
+    #[test]
+    fn mod_position_longer() {
+        let result = Sequencer::mod_position(PPQ as f64, PPQ * 2);
+        assert_eq!(result, PPQ as f64);
+    }
+
+    #[test]
+    fn samples_per_beat_test() {
+        let result = Sequencer::samples_per_beat(44100, 120.);
+        assert_eq!(result, 22050.);
+    }
+
+    #[test]
+    fn samples_per_subtick_test() {
+        let result = Sequencer::samples_per_subtick(44100, 120., PPQ);
+        assert_eq!(result, 229.6875);
+    }
+
+    #[test]
+    fn subtick_position_test() {
+        let result = Sequencer::subtick_position(0.5, PPQ);
+        assert_eq!(result, 48.);
+    }
+
+    #[test]
+    fn render_timeline_offsets() {
+        let mut sequencer = Sequencer::new(config(1024.));
+        let mut midi = Vec::new();
+        // events are 6 ticks (1378.125 samples) apart, so 1024 samples only cover the first one
+        sequencer.render_timeline(0., &mut midi);
+        let offsets: Vec<f64> = midi.iter().map(|e| e.offset()).collect();
+        assert_eq!(offsets, vec![0.]);
+
+        midi.clear();
+        sequencer.render_timeline(5. / PPQ as f64, &mut midi);
+        let offsets: Vec<f64> = midi.iter().map(|e| e.offset()).collect();
+        assert_eq!(offsets, vec![229.6875]);
+    }
+
+    #[test]
+    fn jittery_buffers_play_every_note_once() {
+        let mut sequencer = sequencer_with_notes(vec![note(Note::C4, 2, 1), note(Note::D4, 5, 1)]);
+        let buffer_ticks = sequencer.config.buffer_size / sequencer.ticks_to_samples(1.);
+        let mut midi = Vec::new();
+        sequencer.render_timeline(0., &mut midi);
+        // the second buffer starts a little early and overlaps the first one, the third starts
+        // a little late and leaves a gap
+        for start in [buffer_ticks - 0.3, 2. * buffer_ticks + 0.8] {
+            let mut buffer = Vec::new();
+            sequencer.render_timeline(start / PPQ as f64, &mut buffer);
+            midi.extend(buffer);
+        }
+
+        let note_ons: Vec<Note> = midi
+            .iter()
+            .filter_map(|event| match event.message() {
+                MidiMessage::NoteOn(_, note, _) => Some(*note),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(note_ons, vec![Note::C4, Note::D4]);
+    }
+
+    #[test]
+    fn render_timeline_wraps_around_loop_end() {
+        let mut sequencer = Sequencer::new(config(512.));
+        let mut midi = Vec::new();
+        // one tick before the end of the one beat loop
+        sequencer.render_timeline((PPQ - 1) as f64 / PPQ as f64, &mut midi);
+        assert_eq!(midi.len(), 1);
+        assert_eq!(midi[0].offset(), 229.6875);
+        assert!(matches!(midi[0].message(), MidiMessage::NoteOn(..)));
+    }
+
+    #[test]
+    fn render_timeline_does_not_drift() {
+        let mut sequencer = Sequencer::new(config(512.));
+        let mut midi = Vec::new();
+        // after a million loops, the loop start still lands exactly on the buffer start
+        sequencer.render_timeline(1_000_000., &mut midi);
+        assert_eq!(midi.len(), 1);
+        assert_eq!(midi[0].offset(), 0.);
+    }
+
+    #[test]
+    fn note_off_wraps_around_loop_end() {
+        let mut sequencer = sequencer_with_notes(vec![note(Note::C4, 90, 12)]);
+        let rendered = render_ticks(&mut sequencer, 3.);
+        let note_offs: Vec<f64> = rendered
+            .iter()
+            .filter(|(_, message)| matches!(message, MidiMessage::NoteOff(..)))
+            .map(|(tick, _)| tick.round())
+            .collect();
+        assert_eq!(note_offs, vec![102., 198.]);
+    }
+
+    #[test]
+    fn retrigger_cuts_previous_note() {
+        let mut sequencer =
+            sequencer_with_notes(vec![note(Note::C4, 0, 48), note(Note::C4, 24, 12)]);
+        let rendered = render_ticks(&mut sequencer, 1.);
+        let messages: Vec<(f64, bool)> = rendered
+            .iter()
+            .map(|(tick, message)| (tick.round(), matches!(message, MidiMessage::NoteOn(..))))
+            .collect();
+        // the first note is cut at tick 24, and its original note-off at tick 48 is never sent
+        assert_eq!(
+            messages,
+            vec![(0., true), (24., false), (24., true), (36., false)]
+        );
+    }
+
+    fn note_ons(rendered: &[(f64, MidiMessage<'static>)], note: Note) -> Vec<f64> {
+        rendered
+            .iter()
+            .filter(|(_, message)| matches!(message, MidiMessage::NoteOn(_, n, _) if *n == note))
+            .map(|(tick, _)| (tick * 1e6).round() / 1e6)
+            .collect()
+    }
+
+    #[test]
+    fn polymeters_stay_phase_aligned() {
+        let mut sequencer = sequencer_with_tracks(vec![]);
+        for (beats, pitch) in [(3, Note::C4), (5, Note::D4), (7, Note::E4)] {
+            let mut sequence = MIDISequence::new(PPQ * beats);
+            sequence.add_note(note(pitch, 0, 12));
+            sequencer.add_sequence(sequence);
+        }
+
+        // 420 bars of 4/4
+        let rendered = render_ticks(&mut sequencer, 1680.);
+        for (beats, pitch) in [(3i64, Note::C4), (5, Note::D4), (7, Note::E4)] {
+            let ticks = note_ons(&rendered, pitch);
+            assert_eq!(ticks.len() as i64, (1680 + beats - 1) / beats);
+            for (count, tick) in ticks.iter().enumerate() {
+                let expected = (count as i64 * beats * PPQ) as f64;
+                assert!((tick - expected).abs() < 1e-6, "{} != {}", tick, expected);
+            }
+        }
+
+        // all three sequences line up again every 105 beats
+        let downbeats = |pitch| {
+            note_ons(&rendered, pitch)
+                .into_iter()
+                .filter(|tick| (tick / PPQ as f64).round() as i64 % 105 == 0)
+                .count()
+        };
+        assert_eq!(downbeats(Note::C4), 16);
+        assert_eq!(downbeats(Note::D4), 16);
+        assert_eq!(downbeats(Note::E4), 16);
+    }
+
+    #[test]
+    fn resync_to_quantum() {
+        let mut sequencer = sequencer_with_tracks(vec![]);
+        let mut sequence = MIDISequence::new(PPQ * 3);
+        sequence.add_note(note(Note::C4, 0, 12));
+        let index = sequencer.add_sequence(sequence);
+
+        // re-sync half way through the third bar
+        sequencer.resync_to_quantum(index, 10.5, 4.);
+        assert_eq!(sequencer.sequence_mut(index).unwrap().anchor, 8 * PPQ);
+
+        let mut midi = Vec::new();
+        sequencer.render_timeline(11., &mut midi);
+        assert!(matches!(midi[0].message(), MidiMessage::NoteOn(..)));
+        assert_eq!(midi[0].offset(), 0.);
+    }
+
+    #[test]
+    fn swing_is_applied_at_render_time() {
+        let mut sequencer = sequencer_with_notes(vec![note(Note::C4, 0, 6), note(Note::C4, 24, 6)]);
+        sequencer
+            .sequence_mut(0)
+            .unwrap()
+            .set_swing(Some(Swing::new(75., SwingGrid::Sixteenth)));
+        let rendered = render_ticks(&mut sequencer, 1.);
+        assert_eq!(note_ons(&rendered, Note::C4), vec![0., 36.]);
+        assert_eq!(sequencer.sequence_mut(0).unwrap().notes[1].tick, 24);
+    }
+
+    #[test]
+    fn groove_moves_events_across_loop_start() {
+        let mut sequencer = sequencer_with_notes(vec![note(Note::C4, 0, 6), note(Note::D4, 48, 6)]);
+        let steps = vec![
+            GrooveStep {
+                timing: -2.,
+                velocity: 0.5,
+            },
+            GrooveStep {
+                timing: 3.,
+                velocity: 1.,
+            },
+        ];
+        sequencer
+            .sequence_mut(0)
+            .unwrap()
+            .set_groove(Some(Groove::new(48, steps)));
+        let rendered = render_ticks(&mut sequencer, 3.);
+        assert_eq!(note_ons(&rendered, Note::C4), vec![94., 190., 286.]);
+        assert_eq!(note_ons(&rendered, Note::D4), vec![51., 147., 243.]);
+        let velocities: Vec<u8> = rendered
+            .iter()
+            .filter_map(|(_, message)| match message {
+                MidiMessage::NoteOn(_, Note::C4, velocity) => Some(u8::from(*velocity)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(velocities, vec![50, 50, 50]);
+    }
+
+    #[test]
+    fn humanize_is_reproducible_across_buffer_sizes() {
+        let humanized = || {
+            let notes = (0..16).map(|step| note(Note::C4, step * 6, 3)).collect();
+            let mut sequencer = sequencer_with_notes(notes);
+            sequencer
+                .sequence_mut(0)
+                .unwrap()
+                .set_humanize(Some(Humanize::new(2., 20, 7)));
+            sequencer
+        };
+        let mut small_buffers = humanized();
+        let mut large_buffers = humanized();
+        large_buffers.config.buffer_size = 4096.;
+
+        let small = render_ticks(&mut small_buffers, 8.);
+        let large = render_ticks(&mut large_buffers, 8.);
+        assert_eq!(note_ons(&small, Note::C4), note_ons(&large, Note::C4));
+        assert_ne!(note_ons(&small, Note::C4)[1], 6.);
+    }
+
+    #[test]
+    fn humanized_notes_are_sent_at_their_humanized_tick() {
+        let notes: Vec<SequencerNote> = (0..16).map(|step| note(Note::C4, step * 6, 3)).collect();
+        let humanize = Humanize::new(5., 0, 99);
+        let mut expected: Vec<f64> = (-1..=4)
+            .flat_map(|iteration| {
+                let humanize = &humanize;
+                notes.iter().enumerate().map(move |(index, note)| {
+                    (iteration * PPQ + note.tick()) as f64
+                        + humanize.timing_offset(iteration, index)
+                })
+            })
+            .filter(|tick| (0. ..4. * PPQ as f64).contains(tick))
+            .collect();
+        expected.sort_by(f64::total_cmp);
+
+        let mut sequencer = sequencer_with_notes(notes);
+        sequencer
+            .sequence_mut(0)
+            .unwrap()
+            .set_humanize(Some(humanize.clone()));
+        let mut rendered = note_ons(&render_ticks(&mut sequencer, 4.), Note::C4);
+        rendered.sort_by(f64::total_cmp);
+
+        // a note moved before the start of a buffer would be clamped to its first sample
+        assert_eq!(rendered.len(), expected.len());
+        for (rendered, expected) in rendered.iter().zip(&expected) {
+            assert!((rendered - expected).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn step_conditions_follow_loop_iterations() {
+        let mut sequence = MIDISequence::with_steps(Channel::Ch1, 16, PPQ / 4);
+        let mut first = Step::new(Note::C4, U7::MAX, 6);
+        first.condition = TrigCondition::First;
+        sequence.set_step(0, first);
+        let mut every_other = Step::new(Note::D4, U7::MAX, 6);
+        every_other.condition = TrigCondition::Ratio(2, 2);
+        sequence.set_step(4, every_other);
+        let mut fill = Step::new(Note::E4, U7::MAX, 6);
+        fill.condition = TrigCondition::Fill;
+        sequence.set_step(8, fill);
+        let mut sequencer = sequencer_with_tracks(vec![Track::with_sequence(sequence)]);
+
+        let rendered = render_ticks(&mut sequencer, 16.);
+        assert_eq!(note_ons(&rendered, Note::C4), vec![0.]);
+        assert_eq!(note_ons(&rendered, Note::D4), vec![480., 1248.]);
+        assert!(note_ons(&rendered, Note::E4).is_empty());
+
+        sequencer.set_fill(true);
+        let mut midi = Vec::new();
+        sequencer.render_timeline(18., &mut midi);
+        assert!(matches!(
+            midi[0].message(),
+            MidiMessage::NoteOn(_, Note::E4, _)
+        ));
+    }
+
+    #[test]
+    fn ratchets_are_sample_accurate_across_buffers() {
+        let mut sequence = MIDISequence::with_steps(Channel::Ch1, 4, PPQ / 4);
+        let mut step = Step::new(Note::C4, U7::MAX, 24);
+        step.ratchet = 5;
+        sequence.set_step(1, step);
+        let mut sequencer = sequencer_with_tracks(vec![Track::with_sequence(sequence)]);
+
+        let rendered = render_ticks(&mut sequencer, 1.);
+        assert_eq!(
+            note_ons(&rendered, Note::C4),
+            vec![24., 28.8, 33.6, 38.4, 43.2]
+        );
+    }
+
+    #[test]
+    fn parameter_locks_surround_the_note() {
+        let cutoff = ControlFunction::from(U7::from_u8_lossy(74));
+        let mut sequence = MIDISequence::with_steps(Channel::Ch1, 4, PPQ / 4);
+        let mut step = Step::new(Note::C4, U7::MAX, 12);
+        step.locks = vec![(cutoff, U7::from_u8_lossy(20))];
+        sequence.set_step(1, step);
+        sequence
+            .steps_mut()
+            .unwrap()
+            .set_default(cutoff, U7::from_u8_lossy(64));
+        let mut sequencer = sequencer_with_tracks(vec![Track::with_sequence(sequence)]);
+
+        let rendered: Vec<(f64, String)> = render_ticks(&mut sequencer, 1.)
+            .into_iter()
+            .map(|(tick, message)| {
+                let name = match message {
+                    MidiMessage::ControlChange(_, _, value) => format!("cc {}", u8::from(value)),
+                    MidiMessage::NoteOn(..) => "on".to_string(),
+                    MidiMessage::NoteOff(..) => "off".to_string(),
+                    _ => "other".to_string(),
+                };
+                (tick.round(), name)
+            })
+            .collect();
+        assert_eq!(
+            rendered,
+            vec![
+                (24., "cc 20".to_string()),
+                (24., "on".to_string()),
+                (36., "off".to_string()),
+                (36., "cc 64".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn regenerate_euclid_keeps_playhead() {
+        let euclid = Euclid::new(3, 8, Note::C2, U7::MAX, 6);
+        let sequence = MIDISequence::euclidean(Channel::Ch10, PPQ / 4, &euclid);
+        let mut sequencer = sequencer_with_tracks(vec![Track::with_sequence(sequence)]);
+
+        // on step 13 of the timeline, which is step 5 of the 8 step loop
+        let beat = 13.5 / 4.;
+        sequencer.set_euclid(0, &Euclid::new(4, 12, Note::C2, U7::MAX, 6), beat);
+        let sequence = sequencer.sequence_mut(0).unwrap();
+        assert_eq!(sequence.length, 12 * PPQ / 4);
+        let position =
+            Sequencer::mod_position(beat * PPQ as f64 - sequence.anchor as f64, sequence.length);
+        assert_eq!(position, 5.5 * PPQ as f64 / 4.);
+
+        // the next onset of E(4, 12) after step 5 is step 6
+        let mut midi = Vec::new();
+        sequencer.render_timeline(14. / 4., &mut midi);
+        assert!(matches!(midi[0].message(), MidiMessage::NoteOn(..)));
+        assert_eq!(midi[0].offset(), 0.);
+    }
+
+    #[test]
+    fn arpeggiator_follows_midi_input() {
+        let mut sequencer = sequencer_with_notes(vec![]);
+        let mut arpeggiator = Arpeggiator::new(Channel::Ch2, ArpMode::Up, PPQ / 4);
+        arpeggiator.set_latch(true);
+        sequencer
+            .sequence_mut(0)
+            .unwrap()
+            .set_arpeggiator(Some(arpeggiator));
+
+        for note in [Note::G4, Note::C4] {
+            sequencer.midi_input(&MidiMessage::NoteOn(Channel::Ch1, note, U7::MAX), 0.);
+        }
+        for note in [Note::G4, Note::C4] {
+            sequencer.midi_input(&MidiMessage::NoteOff(Channel::Ch1, note, U7::MIN), 0.);
+        }
+
+        let rendered = render_ticks(&mut sequencer, 1.);
+        assert_eq!(note_ons(&rendered, Note::C4), vec![0., 48.]);
+        assert_eq!(note_ons(&rendered, Note::G4), vec![24., 72.]);
+    }
+
+    #[test]
+    fn arpeggiator_releases_on_zero_velocity_note_on() {
+        let mut sequencer = sequencer_with_notes(vec![]);
+        let arpeggiator = Arpeggiator::new(Channel::Ch2, ArpMode::Up, PPQ / 4);
+        let sequence = sequencer.sequence_mut(0).unwrap();
+        sequence.set_arpeggiator(Some(arpeggiator));
+
+        sequencer.midi_input(&MidiMessage::NoteOn(Channel::Ch1, Note::C4, U7::MAX), 0.);
+        sequencer.midi_input(&MidiMessage::NoteOn(Channel::Ch1, Note::C4, U7::MIN), 0.);
+        let sequence = sequencer.sequence_mut(0).unwrap();
+        assert!(sequence.arpeggiator_mut().unwrap().note_at(0).is_none());
+    }
+
+    #[test]
+    fn record_into_armed_sequence() {
+        let mut sequencer = sequencer_with_notes(vec![]);
+        sequencer
+            .recorder_mut()
+            .set_quantize(Some(Quantize::new(PPQ / 4)));
+        sequencer.midi_input(&MidiMessage::NoteOn(Channel::Ch1, Note::E4, U7::MAX), 0.5);
+        assert!(sequencer.sequence_mut(0).unwrap().notes().is_empty());
+
+        sequencer.arm(Some(0));
+        // played slightly late in the second loop, released with a zero velocity note-on
+        sequencer.midi_input(&MidiMessage::NoteOn(Channel::Ch1, Note::E4, U7::MAX), 1.52);
+        sequencer.midi_input(&MidiMessage::NoteOn(Channel::Ch1, Note::E4, U7::MIN), 1.77);
+        let notes = sequencer.sequence_mut(0).unwrap().notes();
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].tick(), PPQ / 2);
+        assert_eq!(notes[0].length(), PPQ / 4);
+
+        let rendered = render_ticks(&mut sequencer, 2.);
+        assert_eq!(note_ons(&rendered, Note::E4), vec![48., 144.]);
+    }
+
+    #[test]
+    fn quantize_at_render_time_and_as_edit() {
+        let mut sequencer =
+            sequencer_with_notes(vec![note(Note::C4, 27, 10), note(Note::D4, 70, 30)]);
+        let mut quantize = Quantize::new(PPQ / 4);
+        quantize.set_strength(50.);
+        sequencer
+            .sequence_mut(0)
+            .unwrap()
+            .set_quantize(Some(quantize.clone()));
+
+        let rendered = render_ticks(&mut sequencer, 1.);
+        assert_eq!(note_ons(&rendered, Note::C4), vec![25.5]);
+        assert_eq!(note_ons(&rendered, Note::D4), vec![71.]);
+        assert_eq!(sequencer.sequence_mut(0).unwrap().notes()[0].tick(), 27);
+
+        quantize.set_strength(100.);
+        quantize.set_lengths(true);
+        sequencer.sequence_mut(0).unwrap().quantize_notes(&quantize);
+        let notes = sequencer.sequence_mut(0).unwrap().notes();
+        assert_eq!((notes[0].tick(), notes[0].length()), (24, 24));
+        assert_eq!((notes[1].tick(), notes[1].length()), (72, 24));
+
+        let mut empty = MIDISequence::new(0);
+        empty.add_note(note(Note::C4, 27, 10));
+        empty.quantize_notes(&quantize);
+        assert_eq!(empty.notes()[0].tick(), 24);
+    }
+
+    #[test]
+    fn pitch_quantizer_follows_the_key() {
+        let mut sequencer = sequencer_with_notes(vec![note(Note::Db4, 0, 96)]);
+        let chord = Chord::new(3, Voicing::Close);
+        sequencer
+            .sequence_mut(0)
+            .unwrap()
+            .set_pitch_quantizer(Some(PitchQuantizer::new(None, Some(chord))));
+        let velocity = U7::from_u8_lossy(100);
+
+        assert_eq!(
+            messages(&mut sequencer, 0.),
+            vec![
+                MidiMessage::NoteOn(Channel::Ch1, Note::C4, velocity),
+                MidiMessage::NoteOn(Channel::Ch1, Note::E4, velocity),
+                MidiMessage::NoteOn(Channel::Ch1, Note::G4, velocity),
+            ]
+        );
+
+        // a new key retunes the next loop, the chord that is sounding is released as played
+        sequencer.set_key(Scale::new(1, Mode::Major));
+        assert_eq!(
+            messages(&mut sequencer, 1.),
+            vec![
+                MidiMessage::NoteOff(Channel::Ch1, Note::C4, U7::MIN),
+                MidiMessage::NoteOff(Channel::Ch1, Note::E4, U7::MIN),
+                MidiMessage::NoteOff(Channel::Ch1, Note::G4, U7::MIN),
+                MidiMessage::NoteOn(Channel::Ch1, Note::Db4, velocity),
+                MidiMessage::NoteOn(Channel::Ch1, Note::F4, velocity),
+                MidiMessage::NoteOn(Channel::Ch1, Note::Ab4, velocity),
+            ]
+        );
+    }
+
+    #[test]
+    fn pitch_quantizer_applies_to_note_events() {
+        let mut sequencer = sequencer_with_notes(vec![]);
+        let velocity = U7::from_u8_lossy(100);
+        let sequence = sequencer.sequence_mut(0).unwrap();
+        sequence.add_event(SequencerEvent {
+            tick: 0,
+            message: MidiMessage::NoteOn(Channel::Ch1, Note::Db4, velocity),
+        });
+        sequence.add_event(SequencerEvent {
+            tick: 4,
+            message: MidiMessage::NoteOff(Channel::Ch1, Note::Db4, U7::MIN),
+        });
+        let chord = Chord::new(2, Voicing::Close);
+        sequence.set_pitch_quantizer(Some(PitchQuantizer::new(None, Some(chord))));
+
+        assert_eq!(
+            messages(&mut sequencer, 0.),
+            vec![
+                MidiMessage::NoteOn(Channel::Ch1, Note::C4, velocity),
+                MidiMessage::NoteOn(Channel::Ch1, Note::E4, velocity),
+            ]
+        );
+        // the note-off releases the chord that was played, in the old key
+        sequencer.set_key(Scale::new(1, Mode::Major));
+        assert_eq!(
+            messages(&mut sequencer, 512. / 22050.),
+            vec![
+                MidiMessage::NoteOff(Channel::Ch1, Note::C4, U7::MIN),
+                MidiMessage::NoteOff(Channel::Ch1, Note::E4, U7::MIN),
+            ]
+        );
+    }
+
+    #[test]
+    fn note_off_releases_the_transposed_pitch() {
+        let mut sequencer = sequencer_with_notes(vec![]);
+        let velocity = U7::from_u8_lossy(100);
+        let sequence = sequencer.sequence_mut(0).unwrap();
+        sequence.add_event(SequencerEvent {
+            tick: 0,
+            message: MidiMessage::NoteOn(Channel::Ch1, Note::C4, velocity),
+        });
+        sequence.add_event(SequencerEvent {
+            tick: 48,
+            message: MidiMessage::NoteOff(Channel::Ch1, Note::C4, U7::MIN),
+        });
+        sequence.set_transpose(2);
+        sequence.set_octave(-1);
+
+        assert_eq!(
+            messages(&mut sequencer, 0.),
+            vec![MidiMessage::NoteOn(Channel::Ch1, Note::D3, velocity)]
+        );
+        sequencer.set_transpose(5);
+        // buffers follow each other up to the note-off, so playback doesn't jump
+        let buffer = 512. / 22050.;
+        let released: Vec<MidiMessage> = (1..=(0.5 / buffer) as usize)
+            .flat_map(|index| messages(&mut sequencer, index as f64 * buffer))
+            .collect();
+        assert_eq!(
+            released,
+            vec![MidiMessage::NoteOff(Channel::Ch1, Note::D3, U7::MIN)]
+        );
+        assert_eq!(
+            messages(&mut sequencer, 1.),
+            vec![MidiMessage::NoteOn(Channel::Ch1, Note::G3, velocity)]
+        );
+    }
+
+    #[test]
+    fn out_of_range_notes_are_clamped_or_dropped() {
+        let mut sequencer = sequencer_with_notes(vec![note(Note::C4, 0, 12)]);
+        sequencer.set_octave(6);
+        let rendered = render_ticks(&mut sequencer, 1.);
+        assert!(rendered.is_empty());
+
+        sequencer.set_out_of_range(OutOfRange::Clamp);
+        let rendered = render_ticks(&mut sequencer, 1.);
+        assert_eq!(note_ons(&rendered, Note::G9), vec![0.]);
+    }
+
+    #[test]
+    fn song_switches_patterns_on_the_bar() {
+        let mut sequencer =
+            sequencer_with_tracks(vec![track_with_clips(&[Note::C4, Note::D4], PPQ / 2)]);
+
+        let sections = vec![
+            Section::new(vec![Some(0)], 1),
+            Section::new(vec![Some(1)], 1),
+        ];
+        // one beat bars, starting on the second half of the first beat
+        let mut song = Song::new(sections, 1., EndAction::Stop);
+        song.set_start(0.5);
+        sequencer.set_song(Some(song));
+
+        let rendered = render_ticks(&mut sequencer, 4.);
+        assert_eq!(note_ons(&rendered, Note::C4), vec![48., 96.]);
+        assert_eq!(note_ons(&rendered, Note::D4), vec![144., 192.]);
+        assert_eq!(rendered.len(), 8);
+        assert_eq!(sequencer.tracks[0].playing(), None);
+    }
+
+    #[test]
+    fn song_leaves_clips_alone_within_a_section() {
+        let mut sequencer =
+            sequencer_with_tracks(vec![track_with_clips(&[Note::C4, Note::D4], PPQ)]);
+        let sections = vec![
+            Section::new(vec![Some(0)], 2),
+            Section::new(vec![Some(1)], 1),
+        ];
+        sequencer.set_song(Some(Song::new(sections, 1., EndAction::Stop)));
+
+        // the track is stopped halfway through the first beat of a two bar section
+        let buffer_ticks = sequencer.config.buffer_size / sequencer.ticks_to_samples(1.);
+        let mut played = Vec::new();
+        let mut tick = 0.;
+        while tick < 3. * PPQ as f64 {
+            if (tick..tick + buffer_ticks).contains(&(PPQ as f64 / 2.)) {
+                sequencer.tracks[0].stop();
+            }
+            let mut midi = Vec::new();
+            sequencer.render_timeline(tick / PPQ as f64, &mut midi);
+            played.extend(midi.iter().filter_map(|event| match event.message() {
+                MidiMessage::NoteOn(_, note, _) => Some(*note),
+                _ => None,
+            }));
+            tick += buffer_ticks;
+        }
+        assert_eq!(played, vec![Note::C4, Note::D4]);
+    }
+
+    #[test]
+    fn clip_launch_waits_for_the_quantum() {
+        let mut track = track_with_clips(&[Note::C4, Note::D4], PPQ);
+        track.play(0, 0);
+        let mut sequencer = sequencer_with_tracks(vec![track]);
+        sequencer.set_quantum(3.);
+
+        sequencer.launch_clip(0, 1, 0.3);
+        assert_eq!(sequencer.tracks[0].queued(), Some(Some(1)));
+        let rendered = render_ticks(&mut sequencer, 4.);
+        assert_eq!(note_ons(&rendered, Note::C4), vec![0., 96., 192.]);
+        assert_eq!(note_ons(&rendered, Note::D4), vec![288.]);
+
+        // a quantized stop, and a scene launch that replaces it before it is due
+        sequencer.set_launch_quantize(LaunchQuantize::Beat);
+        sequencer.stop_clip(0, 4.5);
+        sequencer.launch_scene(0, 4.6);
+        assert_eq!(sequencer.tracks[0].queued(), Some(Some(0)));
+        let mut midi = Vec::new();
+        sequencer.render_timeline(5., &mut midi);
+        assert_eq!(sequencer.tracks[0].playing(), Some(0));
+        assert_eq!(sequencer.tracks[0].queued(), None);
+    }
+
+    #[test]
+    fn switching_clips_releases_note_events() {
+        let velocity = U7::from_u8_lossy(100);
+        let mut clip = MIDISequence::new(PPQ);
+        clip.add_event(SequencerEvent {
+            tick: 0,
+            message: MidiMessage::NoteOn(Channel::Ch1, Note::C4, velocity),
+        });
+        clip.add_event(SequencerEvent {
+            tick: 90,
+            message: MidiMessage::NoteOff(Channel::Ch1, Note::C4, U7::MIN),
+        });
+        let mut track = Track::new(2);
+        track.set_slot(0, clip);
+        track.set_slot(1, MIDISequence::new(PPQ));
+        track.play(0, 0);
+        let mut sequencer = sequencer_with_tracks(vec![track]);
+
+        assert_eq!(
+            messages(&mut sequencer, 0.),
+            vec![MidiMessage::NoteOn(Channel::Ch1, Note::C4, velocity)]
+        );
+        sequencer.track_mut(0).unwrap().play(1, 0);
+        assert_eq!(
+            messages(&mut sequencer, 512. / 22050.),
+            vec![MidiMessage::NoteOff(Channel::Ch1, Note::C4, U7::MIN)]
+        );
+    }
+
+    #[test]
+    fn stopped_clock_releases_notes() {
+        let mut sequencer = sequencer_with_notes(vec![note(Note::C4, 0, 90)]);
+        let mut clock = ClockInput::new();
+        clock.receive(&MidiMessage::Start, 0);
+        clock.receive(&MidiMessage::TimingClock, 0);
+        let mut midi = Vec::new();
+        sequencer.render_source(&clock, 0, &mut midi);
+        assert_eq!(midi.len(), 1);
+
+        clock.receive(&MidiMessage::Stop, 10_000);
+        assert!(!clock.is_playing());
+        assert_eq!(
+            released(&mut sequencer),
+            vec![(0., MidiMessage::NoteOff(Channel::Ch1, Note::C4, U7::MIN))]
+        );
+    }
+
+    #[test]
+    fn stopped_internal_clock_and_timecode_release_notes() {
+        let note_off = vec![(0., MidiMessage::NoteOff(Channel::Ch1, Note::C4, U7::MIN))];
+        let mut sequencer = sequencer_with_notes(vec![note(Note::C4, 0, 90)]);
+        let mut clock = InternalClock::new(120.);
+        clock.set_playing(true, 0);
+        sequencer.render_source(&clock, 0, &mut Vec::new());
+        clock.set_playing(false, 10_000);
+        assert!(!clock.is_playing(10_000));
+        assert_eq!(released(&mut sequencer), note_off);
+
+        // a tenth of a second of time code, the note is chased once it's followed
+        let mut sequencer = sequencer_with_notes(vec![note(Note::C4, 0, 90)]);
+        sequencer.set_chase(true);
+        let mut output = MtcOutput::new(FrameRate::Fps25);
+        let mut midi = Vec::new();
+        output.render(0., 120., true, 4410., 44100., &mut midi);
+        let mut timecode = MtcInput::new();
+        for event in &midi {
+            let time = (event.offset() / 44100. * 1.0e6) as i64;
+            timecode.receive(event.message(), time);
+        }
+        let mut midi = Vec::new();
+        sequencer.render_source(&timecode, 100_000, &mut midi);
+        for e in &midi { eprintln!("{} {:?}", e.offset(), e.message()); }
+        assert_eq!(midi.len(), 1);
+        // no quarter frames for more than four frames
+        assert!(!timecode.is_playing(300_000));
+        assert_eq!(released(&mut sequencer), note_off);
+    }
+
+    #[test]
+    fn follow_actions_chain_clips() {
+        let mut track = track_with_clips(&[Note::C4, Note::D4, Note::E4], PPQ);
+        for slot in 0..3 {
+            let actions = match slot {
+                0 => vec![(FollowAction::Next, 1)],
+                1 => vec![(FollowAction::Next, 1), (FollowAction::Previous, 0)],
+                _ => vec![(FollowAction::Stop, 1)],
+            };
+            let actions = FollowActions::new(slot as u32 + 1, actions);
+            track
+                .slot_mut(slot)
+                .unwrap()
+                .set_follow_actions(Some(actions));
+        }
+        let mut sequencer = sequencer_with_tracks(vec![track]);
+        sequencer.set_launch_quantize(LaunchQuantize::Immediate);
+        sequencer.launch_clip(0, 0, 0.);
+
+        let rendered = render_ticks(&mut sequencer, 8.);
+        assert_eq!(note_ons(&rendered, Note::C4), vec![0.]);
+        assert_eq!(note_ons(&rendered, Note::D4), vec![96., 192.]);
+        assert_eq!(note_ons(&rendered, Note::E4), vec![288., 384., 480.]);
+        assert_eq!(sequencer.tracks[0].playing(), None);
+    }
+
+    #[test]
+    fn mute_releases_notes_and_unmute_chases() {
+        let velocity = U7::from_u8_lossy(100);
+        let note_on = MidiMessage::NoteOn(Channel::Ch1, Note::C4, velocity);
+        let note_off = MidiMessage::NoteOff(Channel::Ch1, Note::C4, U7::MIN);
+
+        for chase in [false, true] {
+            let mut sequence = MIDISequence::new(2 * PPQ);
+            sequence.add_note(note(Note::C4, 0, 150));
+            let mut track = Track::with_sequence(sequence);
+            track.set_chase(chase);
+            let mut sequencer = sequencer_with_tracks(vec![track]);
+
+            assert_eq!(
+                render_buffer(&mut sequencer, 0.),
+                vec![(0., note_on.clone())]
+            );
+            sequencer.track_mut(0).unwrap().set_muted(true);
+            assert_eq!(
+                render_buffer(&mut sequencer, 0.5),
+                vec![(0., note_off.clone())]
+            );
+            assert!(render_buffer(&mut sequencer, 0.75).is_empty());
+
+            sequencer.track_mut(0).unwrap().set_muted(false);
+            if chase {
+                assert_eq!(
+                    render_buffer(&mut sequencer, 1.),
+                    vec![(0., note_on.clone())]
+                );
+                assert_eq!(
+                    render_buffer(&mut sequencer, 150. / PPQ as f64),
+                    vec![(0., note_off.clone())]
+                );
+            } else {
+                assert!(render_buffer(&mut sequencer, 1.).is_empty());
+                assert!(render_buffer(&mut sequencer, 150. / PPQ as f64).is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn solo_silences_other_tracks() {
+        let mut sequencer = sequencer_with_notes(vec![note(Note::C4, 0, 12)]);
+        sequencer.add_sequence(sequencer.tracks[0].sequence().unwrap().clone());
+        sequencer.add_sequence(sequencer.tracks[0].sequence().unwrap().clone());
+        sequencer.track_mut(1).unwrap().set_solo(true);
+        sequencer.track_mut(2).unwrap().set_solo(true);
+        sequencer.track_mut(2).unwrap().set_enabled(false);
+
+        let rendered = render_ticks(&mut sequencer, 1.);
+        assert_eq!(note_ons(&rendered, Note::C4), vec![0.]);
+        assert!(!sequencer.tracks[0].audible);
+        assert!(sequencer.tracks[1].audible);
+    }
+
+    #[test]
+    fn chase_notes_and_controllers_on_jump() {
+        let cc = |value| {
+            MidiMessage::ControlChange(
+                Channel::Ch1,
+                ControlFunction::MODULATION_WHEEL,
+                U7::from_u8_lossy(value),
+            )
+        };
+        let note_on = MidiMessage::NoteOn(Channel::Ch1, Note::C4, U7::from_u8_lossy(100));
+        let note_off = MidiMessage::NoteOff(Channel::Ch1, Note::C4, U7::MIN);
+
+        for chase in [false, true] {
+            let mut sequence = MIDISequence::new(2 * PPQ);
+            sequence.add_note(note(Note::C4, 0, 150));
+            for (tick, value) in [(10, 20), (50, 40)] {
+                sequence.add_event(SequencerEvent {
+                    tick,
+                    message: cc(value),
+                });
+            }
+            let mut sequencer = sequencer_with_tracks(vec![Track::with_sequence(sequence)]);
+            sequencer.set_chase(chase);
+
+            // starting in the middle of the note
+            let started = messages(&mut sequencer, 1.);
+            let continued = messages(&mut sequencer, 1. + 512. / 22050.);
+            // jumping back releases the note, and chases it again
+            let jumped = messages(&mut sequencer, 0.25);
+            if chase {
+                assert_eq!(started, vec![cc(40), note_on.clone()]);
+                assert_eq!(jumped, vec![note_off.clone(), cc(20), note_on.clone()]);
+            } else {
+                assert!(started.is_empty());
+                assert!(jumped.is_empty());
+            }
+            assert!(continued.is_empty());
+        }
+    }
+
+    #[test]
+    fn render_from_timing_source() {
+        let mut sequencer = sequencer_with_notes(vec![note(Note::C4, 48, 12)]);
+        let source = FakeTiming {
+            tempo: 60.,
+            beat: 0.,
+            playing: true,
+        };
+        // at 60 bpm, the note half a beat in starts 5 ms after a buffer at 495 ms
+        let mut midi = Vec::new();
+        sequencer.render_source(&source, 495_000, &mut midi);
+        assert_eq!(midi.len(), 1);
+        assert!(
+            (midi[0].offset() - 220.5).abs() < 1e-6,
+            "{}",
+            midi[0].offset()
+        );
+    }
+
+    #[test]
+    fn transport_starts_and_stops_on_the_quantum() {
+        let mut sequencer = sequencer_with_notes(vec![note(Note::C4, 0, 12)]);
+        sequencer.stop(0.5, LaunchQuantize::Bar);
+        assert_eq!(
+            note_ons(&render_ticks(&mut sequencer, 12.), Note::C4),
+            vec![0., 96., 192., 288.]
+        );
+
+        sequencer.start(5., LaunchQuantize::Bar);
+        assert_eq!(
+            note_ons(&render_ticks(&mut sequencer, 12.), Note::C4),
+            vec![768., 864., 960., 1056.]
+        );
+
+        let mut sequencer = sequencer_with_notes(vec![note(Note::C4, 0, 12)]);
+        sequencer.stop(0., LaunchQuantize::Immediate);
+        assert!(render_ticks(&mut sequencer, 4.).is_empty());
     }
 }
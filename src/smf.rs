@@ -0,0 +1,235 @@
+use crate::sequencer::SequencerNote;
+use wmidi::{Channel, Note, U7};
+
+const HEADER_CHUNK: &[u8] = b"MThd";
+const TRACK_CHUNK: &[u8] = b"MTrk";
+
+#[derive(Debug, PartialEq)]
+pub enum SmfError {
+    NotAMidiFile,
+    /// SMPTE time divisions have no notion of beats, so they can't be mapped to ticks. Neither
+    /// can a division of zero ticks per quarter note.
+    UnsupportedDivision,
+    UnexpectedEnd,
+}
+
+/// Reads the notes of all tracks in a Standard MIDI File, with their positions converted to
+/// ticks at the given PPQ. Files with the same PPQ as the sequencer are read without any loss.
+pub fn read_notes(bytes: &[u8], ppq: i64) -> Result<Vec<SequencerNote>, SmfError> {
+    let mut reader = Reader { bytes, position: 0 };
+
+    if reader.take(4)? != HEADER_CHUNK {
+        return Err(SmfError::NotAMidiFile);
+    }
+    let header_length = reader.u32()? as usize;
+    let _format = reader.u16()?;
+    let track_count = reader.u16()?;
+    let division = reader.u16()?;
+    if division & 0x8000 != 0 || division == 0 {
+        return Err(SmfError::UnsupportedDivision);
+    }
+    reader.take(header_length.saturating_sub(6))?;
+
+    let mut notes = Vec::new();
+    for _ in 0..track_count {
+        let id = reader.take(4)?;
+        let length = reader.u32()? as usize;
+        let chunk = reader.take(length)?;
+        if id == TRACK_CHUNK {
+            read_track(chunk, &mut notes)?;
+        }
+    }
+
+    let division = division as i64;
+    let mut notes: Vec<SequencerNote> = notes
+        .into_iter()
+        .map(|(channel, note, velocity, tick, length)| {
+            SequencerNote::new(
+                Channel::from_index(channel).unwrap(),
+                Note::from_u8_lossy(note),
+                U7::from_u8_lossy(velocity),
+                rescale(tick, division, ppq),
+                rescale(length, division, ppq),
+            )
+        })
+        .collect();
+    notes.sort_by_key(|note| note.tick());
+
+    Ok(notes)
+}
+
+/// Collects (channel, note, velocity, tick, length) for every note in a track chunk.
+fn read_track(bytes: &[u8], notes: &mut Vec<(u8, u8, u8, i64, i64)>) -> Result<(), SmfError> {
+    let mut reader = Reader { bytes, position: 0 };
+    let mut tick: i64 = 0;
+    let mut running_status: u8 = 0;
+    // notes that have been switched on, as (channel, note, velocity, tick)
+    let mut pending: Vec<(u8, u8, u8, i64)> = Vec::new();
+
+    while !reader.is_empty() {
+        tick += reader.varlen()? as i64;
+
+        let mut status = reader.u8()?;
+        match status {
+            0xFF => {
+                let _kind = reader.u8()?;
+                let length = reader.varlen()? as usize;
+                reader.take(length)?;
+                continue;
+            }
+            0xF0 | 0xF7 => {
+                let length = reader.varlen()? as usize;
+                reader.take(length)?;
+                continue;
+            }
+            _ if status & 0x80 == 0 => {
+                // running status, the byte we just read is the first data byte
+                reader.position -= 1;
+                status = running_status;
+            }
+            _ => running_status = status,
+        }
+
+        let channel = status & 0x0F;
+        let data = match status & 0xF0 {
+            0xC0 | 0xD0 => [reader.u8()?, 0],
+            _ => [reader.u8()?, reader.u8()?],
+        };
+        match (status & 0xF0, data) {
+            (0x90, [note, velocity]) if velocity > 0 => {
+                pending.push((channel, note, velocity, tick));
+            }
+            (0x80, [note, _]) | (0x90, [note, _]) => {
+                if let Some(index) = pending
+                    .iter()
+                    .position(|(c, n, _, _)| *c == channel && *n == note)
+                {
+                    let (channel, note, velocity, start) = pending.remove(index);
+                    notes.push((channel, note, velocity, start, tick - start));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // notes that are never switched off last until the end of the track
+    for (channel, note, velocity, start) in pending {
+        notes.push((channel, note, velocity, start, tick - start));
+    }
+
+    Ok(())
+}
+
+fn rescale(tick: i64, from: i64, to: i64) -> i64 {
+    (tick * to * 2 + from) / (from * 2)
+}
+
+struct Reader<'b> {
+    bytes: &'b [u8],
+    position: usize,
+}
+
+impl<'b> Reader<'b> {
+    fn is_empty(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'b [u8], SmfError> {
+        let end = self.position + count;
+        let bytes = self
+            .bytes
+            .get(self.position..end)
+            .ok_or(SmfError::UnexpectedEnd)?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, SmfError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, SmfError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, SmfError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Variable length quantity, 7 bits per byte with the high bit set on all but the last byte
+    fn varlen(&mut self) -> Result<u32, SmfError> {
+        let mut value: u32 = 0;
+        for _ in 0..4 {
+            let byte = self.u8()?;
+            value = (value << 7) | (byte & 0x7F) as u32;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A single track file at 480 PPQ
+    fn smf(track: &[u8]) -> Vec<u8> {
+        with_division(track, [0x01, 0xE0])
+    }
+
+    /// A single track file with the given time division
+    fn with_division(track: &[u8], division: [u8; 2]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"MThd");
+        bytes.extend_from_slice(&[0, 0, 0, 6, 0, 0, 0, 1]);
+        bytes.extend_from_slice(&division);
+        bytes.extend_from_slice(b"MTrk");
+        bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(track);
+        bytes
+    }
+
+    #[test]
+    fn read_notes_rescales_ticks() {
+        #[rustfmt::skip]
+        let bytes = smf(&[
+            // tempo meta event
+            0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20,
+            // C4 on, off after a quarter note (480 = 0x83 0x60) as a zero velocity note on with
+            // running status
+            0x00, 0x90, 60, 100,
+            0x83, 0x60, 60, 0,
+            // D4 on with running status, off after an eighth note
+            0x00, 62, 80,
+            0x81, 0x70, 0x80, 62, 0,
+            // end of track
+            0x00, 0xFF, 0x2F, 0x00,
+        ]);
+        let notes = read_notes(&bytes, 96).unwrap();
+
+        assert_eq!(notes.len(), 2);
+        assert_eq!(notes[0].tick(), 0);
+        assert_eq!(notes[0].length(), 96);
+        assert_eq!(notes[1].tick(), 96);
+        assert_eq!(notes[1].length(), 48);
+        assert_eq!(u8::from(notes[1].velocity()), 80);
+    }
+
+    #[test]
+    fn read_notes_rejects_other_files() {
+        assert_eq!(read_notes(b"RIFF", 96).err(), Some(SmfError::NotAMidiFile));
+        assert_eq!(read_notes(b"MThd", 96).err(), Some(SmfError::UnexpectedEnd));
+
+        let end_of_track = [0x00, 0xFF, 0x2F, 0x00];
+        for division in [[0, 0], [0xE8, 0x28]] {
+            assert_eq!(
+                read_notes(&with_division(&end_of_track, division), 96).err(),
+                Some(SmfError::UnsupportedDivision)
+            );
+        }
+    }
+}
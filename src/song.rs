@@ -0,0 +1,251 @@
+/// A jump back (or ahead) to another section, taken a number of times.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Jump {
+    pub to: usize,
+    pub times: u32,
+}
+
+/// A part of the arrangement, e.g. a verse or a chorus.
+#[derive(Clone, Debug)]
+pub struct Section {
+    /// The pattern slot each track plays, by track index. Tracks without a pattern are silent.
+    pub patterns: Vec<Option<usize>>,
+    /// Length in bars
+    pub bars: u32,
+    /// Number of times the section is played. Patterns restart on every repeat.
+    pub repeat: u32,
+    /// Where to go after the section has been repeated
+    pub jump: Option<Jump>,
+}
+
+impl Section {
+    pub fn new(patterns: Vec<Option<usize>>, bars: u32) -> Self {
+        Self {
+            patterns,
+            bars,
+            repeat: 1,
+            jump: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EndAction {
+    /// Stop all tracks
+    Stop,
+    /// Start over from the first section
+    Loop,
+    /// Keep repeating the last section
+    Hold,
+}
+
+/// One play of a section on the timeline.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Occurrence {
+    pub section: usize,
+    /// First bar of the occurrence, counted from the start of the song
+    pub bar: i64,
+}
+
+/// A chain of sections laid out on a bar based timeline. The song position is derived from the
+/// beat position alone, so peers that share a Link session and a song start beat play the same
+/// section at the same time.
+#[derive(Clone, Debug)]
+pub struct Song {
+    sections: Vec<Section>,
+    end: EndAction,
+    /// Beat at which the song starts
+    start: f64,
+    beats_per_bar: f64,
+    /// The sections in play order, with repeats and jumps unrolled
+    arrangement: Vec<Occurrence>,
+    /// Length of the arrangement in bars
+    bars: i64,
+}
+
+impl Song {
+    /// Jumps are unrolled into a fixed arrangement, which is capped at this many occurrences.
+    const MAX_OCCURRENCES: usize = 4096;
+
+    pub fn new(sections: Vec<Section>, beats_per_bar: f64, end: EndAction) -> Self {
+        let mut song = Self {
+            sections,
+            end,
+            start: 0.,
+            beats_per_bar,
+            arrangement: Vec::new(),
+            bars: 0,
+        };
+        song.arrange();
+        song
+    }
+
+    pub fn set_start(&mut self, beat: f64) {
+        self.start = beat;
+    }
+
+    pub fn set_end(&mut self, end: EndAction) {
+        self.end = end;
+    }
+
+    pub fn start(&self) -> f64 {
+        self.start
+    }
+
+    pub fn beats_per_bar(&self) -> f64 {
+        self.beats_per_bar
+    }
+
+    pub fn section(&self, index: usize) -> Option<&Section> {
+        self.sections.get(index)
+    }
+
+    fn arrange(&mut self) {
+        let mut jumps_left: Vec<Option<u32>> = self
+            .sections
+            .iter()
+            .map(|section| section.jump.map(|jump| jump.times))
+            .collect();
+        let mut arrangement = Vec::new();
+        let mut bar = 0;
+        let mut index = 0;
+        while index < self.sections.len() && arrangement.len() < Self::MAX_OCCURRENCES {
+            let section = &self.sections[index];
+            for _ in 0..section.repeat {
+                arrangement.push(Occurrence {
+                    section: index,
+                    bar,
+                });
+                bar += section.bars as i64;
+            }
+            index = match (section.jump, &mut jumps_left[index]) {
+                (Some(jump), Some(left)) if *left > 0 => {
+                    *left -= 1;
+                    jump.to
+                }
+                _ => index + 1,
+            };
+        }
+        self.arrangement = arrangement;
+        self.bars = bar;
+    }
+
+    /// The bar of the song at `beat`, counted from the start of the song. A beat that is off
+    /// the bar line by rounding errors only counts as being on it.
+    pub fn bar_at(&self, beat: f64) -> i64 {
+        ((beat - self.start) / self.beats_per_bar + 1e-9).floor() as i64
+    }
+
+    /// The beat at which `bar` starts.
+    pub fn bar_start(&self, bar: i64) -> f64 {
+        self.start + bar as f64 * self.beats_per_bar
+    }
+
+    /// The section that plays in `bar`, with the bar its current occurrence started at on the
+    /// song timeline. `None` before the start and after the end of a song that stops.
+    pub fn occurrence_at(&self, bar: i64) -> Option<Occurrence> {
+        if bar < 0 || self.bars == 0 {
+            return None;
+        }
+        let (position, pass_start) = if bar < self.bars {
+            (bar, 0)
+        } else {
+            match self.end {
+                EndAction::Stop => return None,
+                EndAction::Loop => (bar % self.bars, bar - bar % self.bars),
+                EndAction::Hold => {
+                    let last = self.arrangement.last()?;
+                    let length = (self.sections[last.section].bars as i64).max(1);
+                    let held = (bar - last.bar) / length * length;
+                    return Some(Occurrence {
+                        section: last.section,
+                        bar: last.bar + held,
+                    });
+                }
+            }
+        };
+        let index = self
+            .arrangement
+            .partition_point(|occurrence| occurrence.bar <= position);
+        let occurrence = &self.arrangement[index.checked_sub(1)?];
+        Some(Occurrence {
+            section: occurrence.section,
+            bar: pass_start + occurrence.bar,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sections_played(song: &Song, bars: i64) -> Vec<Option<usize>> {
+        (0..bars)
+            .map(|bar| song.occurrence_at(bar).map(|occurrence| occurrence.section))
+            .collect()
+    }
+
+    #[test]
+    fn repeats_and_jumps() {
+        let intro = Section::new(vec![Some(0)], 1);
+        let mut verse = Section::new(vec![Some(1)], 2);
+        verse.repeat = 2;
+        let mut chorus = Section::new(vec![Some(2)], 1);
+        chorus.jump = Some(Jump { to: 1, times: 1 });
+        let outro = Section::new(vec![None], 1);
+        let song = Song::new(vec![intro, verse, chorus, outro], 4., EndAction::Stop);
+
+        // intro, verse twice, chorus, verse twice, chorus, outro
+        assert_eq!(
+            sections_played(&song, 13),
+            vec![
+                Some(0),
+                Some(1),
+                Some(1),
+                Some(1),
+                Some(1),
+                Some(2),
+                Some(1),
+                Some(1),
+                Some(1),
+                Some(1),
+                Some(2),
+                Some(3),
+                None,
+            ]
+        );
+        // the second repeat of the verse restarts its patterns
+        assert_eq!(song.occurrence_at(4).unwrap().bar, 3);
+        assert_eq!(song.occurrence_at(-1), None);
+    }
+
+    #[test]
+    fn end_actions() {
+        let sections = vec![
+            Section::new(vec![Some(0)], 1),
+            Section::new(vec![Some(1)], 2),
+        ];
+        let mut song = Song::new(sections, 4., EndAction::Loop);
+        song.set_start(8.);
+        assert_eq!(song.bar_at(7.9), -1);
+        assert_eq!(song.bar_at(20.), 3);
+        assert_eq!(
+            song.occurrence_at(3),
+            Some(Occurrence { section: 0, bar: 3 })
+        );
+        assert_eq!(
+            song.occurrence_at(5),
+            Some(Occurrence { section: 1, bar: 4 })
+        );
+
+        song.set_end(EndAction::Hold);
+        assert_eq!(
+            song.occurrence_at(6),
+            Some(Occurrence { section: 1, bar: 5 })
+        );
+        assert_eq!(
+            song.occurrence_at(4),
+            Some(Occurrence { section: 1, bar: 3 })
+        );
+    }
+}
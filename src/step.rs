@@ -0,0 +1,322 @@
+use crate::random;
+use crate::sequencer::SequencerNote;
+use wmidi::{Channel, ControlFunction, Note, U7};
+
+/// Elektron style trig conditions, evaluated against the loop iteration at render time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrigCondition {
+    Always,
+    /// Plays on loop `a` of every `b` loops, e.g. 1:2 or 3:4
+    Ratio(u8, u8),
+    /// Plays only while fill mode is on
+    Fill,
+    /// Plays only while fill mode is off
+    NotFill,
+    /// Plays only on the first loop after the sequence was started or reset
+    First,
+}
+
+impl TrigCondition {
+    pub fn is_met(&self, iteration: i64, fill: bool) -> bool {
+        match *self {
+            TrigCondition::Always => true,
+            TrigCondition::Ratio(a, b) => {
+                b > 0 && iteration.rem_euclid(b as i64) == (a as i64 - 1).rem_euclid(b as i64)
+            }
+            TrigCondition::Fill => fill,
+            TrigCondition::NotFill => !fill,
+            TrigCondition::First => iteration == 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Step {
+    pub note: Note,
+    pub velocity: U7,
+    /// Gate length in ticks
+    pub gate: i64,
+    /// Chance that the step plays, in percent
+    pub probability: u8,
+    pub condition: TrigCondition,
+    /// Number of evenly spaced retriggers within the step, 1 plays the step once
+    pub ratchet: u8,
+    /// Velocity change from one retrigger to the next
+    pub ratchet_ramp: i8,
+    /// Parameter locks, CC values that are sent just before the step plays
+    pub locks: Vec<(ControlFunction, U7)>,
+}
+
+/// A note played by a step. Retriggers of a ratchet are spaced evenly within the step, so they
+/// can start in between ticks.
+#[derive(Clone, Debug)]
+pub struct StepNote {
+    pub index: usize,
+    /// Position in ticks after the start of the step
+    pub offset: f64,
+    pub note: SequencerNote,
+    /// CC values to send just before the note-on
+    pub locks: Vec<(ControlFunction, U7)>,
+    /// CC values to send after the note-off
+    pub restore: Vec<(ControlFunction, U7)>,
+}
+
+impl Step {
+    pub fn new(note: Note, velocity: U7, gate: i64) -> Self {
+        Self {
+            note,
+            velocity,
+            gate,
+            probability: 100,
+            condition: TrigCondition::Always,
+            ratchet: 1,
+            ratchet_ramp: 0,
+            locks: Vec::new(),
+        }
+    }
+}
+
+/// A hardware style grid of equally long steps, each of which can hold a trig.
+#[derive(Clone, Debug)]
+pub struct StepGrid {
+    channel: Channel,
+    /// Length of one step in ticks
+    step_length: i64,
+    steps: Vec<Option<Step>>,
+    /// Track default CC values, restored after a parameter lock
+    defaults: Vec<(ControlFunction, U7)>,
+    /// Seed for step probabilities. Without one, the caller's default is used, so that grids
+    /// with the same layout on different tracks don't play the same random outcomes.
+    seed: Option<u64>,
+}
+
+impl StepGrid {
+    pub fn new(channel: Channel, step_count: usize, step_length: i64) -> Self {
+        Self {
+            channel,
+            step_length,
+            steps: vec![None; step_count],
+            defaults: Vec::new(),
+            seed: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Grid length in ticks
+    pub fn length(&self) -> i64 {
+        self.steps.len() as i64 * self.step_length
+    }
+
+    pub fn step_length(&self) -> i64 {
+        self.step_length
+    }
+
+    /// Change the number of steps, keeping the trigs of the steps that remain.
+    pub fn resize(&mut self, step_count: usize) {
+        self.steps.resize(step_count, None);
+    }
+
+    pub fn step(&self, index: usize) -> Option<&Step> {
+        self.steps.get(index).and_then(|step| step.as_ref())
+    }
+
+    pub fn step_mut(&mut self, index: usize) -> Option<&mut Step> {
+        self.steps.get_mut(index).and_then(|step| step.as_mut())
+    }
+
+    pub fn set_step(&mut self, index: usize, step: Step) {
+        if let Some(slot) = self.steps.get_mut(index) {
+            *slot = Some(step);
+        }
+    }
+
+    pub fn clear_step(&mut self, index: usize) {
+        if let Some(slot) = self.steps.get_mut(index) {
+            *slot = None;
+        }
+    }
+
+    /// Set the track default value of a CC. Parameter locks of CCs without a default are not
+    /// restored.
+    pub fn set_default(&mut self, control: ControlFunction, value: U7) {
+        self.defaults.retain(|(c, _)| *c != control);
+        self.defaults.push((control, value));
+    }
+
+    pub fn set_seed(&mut self, seed: Option<u64>) {
+        self.seed = seed;
+    }
+
+    /// The steps that play in loop `iteration`, as notes positioned relative to the loop start.
+    /// `default_seed` decides step probabilities when the grid has no seed set.
+    pub fn notes(&self, iteration: i64, fill: bool, default_seed: u64) -> Vec<StepNote> {
+        let seed = self.seed.unwrap_or(default_seed);
+        let mut notes = Vec::new();
+        for (index, step) in self.steps.iter().enumerate() {
+            let step = match step {
+                Some(step) => step,
+                None => continue,
+            };
+            if !step.condition.is_met(iteration, fill) {
+                continue;
+            }
+            let chance = random::unit(seed, &[iteration, index as i64]) * 100.;
+            if chance >= step.probability as f64 {
+                continue;
+            }
+
+            let tick = index as i64 * self.step_length;
+            let count = step.ratchet.max(1);
+            let spacing = self.step_length as f64 / count as f64;
+            let gate = (step.gate / count as i64).max(1);
+            let restore: Vec<(ControlFunction, U7)> = self
+                .defaults
+                .iter()
+                .filter(|(control, _)| step.locks.iter().any(|(c, _)| c == control))
+                .cloned()
+                .collect();
+            // locks are sent before the first retrigger and restored after the last one
+            for repeat in 0..count {
+                let velocity =
+                    u8::from(step.velocity) as i64 + step.ratchet_ramp as i64 * repeat as i64;
+                let velocity = U7::from_u8_lossy(velocity.clamp(1, 127) as u8);
+                notes.push(StepNote {
+                    index,
+                    offset: spacing * repeat as f64,
+                    note: SequencerNote::new(self.channel, step.note, velocity, tick, gate),
+                    locks: if repeat == 0 {
+                        step.locks.clone()
+                    } else {
+                        Vec::new()
+                    },
+                    restore: if repeat == count - 1 {
+                        restore.clone()
+                    } else {
+                        Vec::new()
+                    },
+                });
+            }
+        }
+        notes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid_with(step: Step) -> StepGrid {
+        let mut grid = StepGrid::new(Channel::Ch1, 16, 6);
+        grid.set_step(0, step);
+        grid
+    }
+
+    fn plays(grid: &StepGrid, iteration: i64, fill: bool) -> bool {
+        !grid.notes(iteration, fill, 0).is_empty()
+    }
+
+    #[test]
+    fn ratio_conditions() {
+        let mut step = Step::new(Note::C4, U7::MAX, 3);
+        step.condition = TrigCondition::Ratio(3, 4);
+        let grid = grid_with(step);
+        let played: Vec<bool> = (0..8)
+            .map(|iteration| plays(&grid, iteration, false))
+            .collect();
+        assert_eq!(
+            played,
+            vec![false, false, true, false, false, false, true, false]
+        );
+    }
+
+    #[test]
+    fn fill_and_first_conditions() {
+        let mut step = Step::new(Note::C4, U7::MAX, 3);
+        step.condition = TrigCondition::Fill;
+        let fill = grid_with(step.clone());
+        assert!(plays(&fill, 3, true));
+        assert!(!plays(&fill, 3, false));
+
+        step.condition = TrigCondition::NotFill;
+        let not_fill = grid_with(step.clone());
+        assert!(!plays(&not_fill, 3, true));
+        assert!(plays(&not_fill, 3, false));
+
+        step.condition = TrigCondition::First;
+        let first = grid_with(step);
+        assert!(plays(&first, 0, false));
+        assert!(!plays(&first, 1, false));
+    }
+
+    #[test]
+    fn probability() {
+        let mut step = Step::new(Note::C4, U7::MAX, 3);
+        step.probability = 25;
+        let grid = grid_with(step);
+        let count = (0..1000)
+            .filter(|iteration| plays(&grid, *iteration, false))
+            .count();
+        assert!((200..300).contains(&count), "{}", count);
+    }
+
+    #[test]
+    fn default_seed() {
+        let mut step = Step::new(Note::C4, U7::MAX, 3);
+        step.probability = 50;
+        let mut grid = grid_with(step);
+        let outcomes = |grid: &StepGrid, default_seed| -> Vec<bool> {
+            (0..32)
+                .map(|iteration| !grid.notes(iteration, false, default_seed).is_empty())
+                .collect()
+        };
+        assert_ne!(outcomes(&grid, 0), outcomes(&grid, 1));
+
+        // a seed of the grid's own wins over the default
+        grid.set_seed(Some(7));
+        assert_eq!(outcomes(&grid, 0), outcomes(&grid, 1));
+    }
+
+    #[test]
+    fn ratchet() {
+        let mut step = Step::new(Note::C4, U7::from_u8_lossy(100), 5);
+        step.ratchet = 4;
+        step.ratchet_ramp = -30;
+        let mut grid = StepGrid::new(Channel::Ch1, 16, 6);
+        grid.set_step(2, step);
+        let notes = grid.notes(0, false, 0);
+
+        let offsets: Vec<f64> = notes.iter().map(|note| note.offset).collect();
+        assert_eq!(offsets, vec![0., 1.5, 3., 4.5]);
+        let velocities: Vec<u8> = notes
+            .iter()
+            .map(|note| u8::from(note.note.velocity()))
+            .collect();
+        assert_eq!(velocities, vec![100, 70, 40, 10]);
+        assert!(notes.iter().all(|note| note.note.tick() == 12));
+        assert!(notes.iter().all(|note| note.note.length() == 1));
+    }
+
+    #[test]
+    fn parameter_locks_are_restored_to_defaults() {
+        let cutoff = ControlFunction::from(U7::from_u8_lossy(74));
+        let resonance = ControlFunction::from(U7::from_u8_lossy(71));
+        let mut step = Step::new(Note::C4, U7::MAX, 3);
+        step.ratchet = 2;
+        step.locks = vec![(cutoff, U7::from_u8_lossy(20)), (resonance, U7::MAX)];
+        let mut grid = grid_with(step);
+        grid.set_default(cutoff, U7::from_u8_lossy(64));
+
+        let notes = grid.notes(0, false, 0);
+        assert_eq!(notes[0].locks.len(), 2);
+        assert!(notes[0].restore.is_empty());
+        assert!(notes[1].locks.is_empty());
+        assert_eq!(notes[1].restore, vec![(cutoff, U7::from_u8_lossy(64))]);
+    }
+}
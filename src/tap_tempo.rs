@@ -0,0 +1,79 @@
+/// Taps further apart than this, in microseconds, start a new count.
+const RESET_AFTER: i64 = 2_000_000;
+
+/// Intervals that differ from the median by more than this fraction are left out of the
+/// average, e.g. a missed or a doubled tap.
+const TOLERANCE: f64 = 0.25;
+
+/// The tempo range of a Link session
+const MIN_TEMPO: f64 = 20.;
+const MAX_TEMPO: f64 = 999.;
+
+/// Derives a tempo from taps on a button. Times are in microseconds on any clock, as long as
+/// it's the same for every tap.
+pub struct TapTempo {
+    /// Number of intervals between taps that are averaged
+    size: usize,
+    taps: Vec<i64>,
+}
+
+impl TapTempo {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size: size.max(1),
+            taps: Vec::new(),
+        }
+    }
+
+    /// Registers a tap at `time`. Returns the tempo from the last taps, from the second tap on.
+    pub fn tap(&mut self, time: i64) -> Option<f64> {
+        if self
+            .taps
+            .last()
+            .is_some_and(|last| time - last > RESET_AFTER || time <= *last)
+        {
+            self.taps.clear();
+        }
+        self.taps.push(time);
+        if self.taps.len() > self.size + 1 {
+            self.taps.remove(0);
+        }
+
+        let mut intervals: Vec<i64> = self.taps.windows(2).map(|taps| taps[1] - taps[0]).collect();
+        intervals.sort_unstable();
+        let median = *intervals.get(intervals.len() / 2)? as f64;
+        let kept: Vec<f64> = intervals
+            .into_iter()
+            .map(|interval| interval as f64)
+            .filter(|interval| (interval - median).abs() <= median * TOLERANCE)
+            .collect();
+        let average = kept.iter().sum::<f64>() / kept.len() as f64;
+        Some((60.0e6 / average).clamp(MIN_TEMPO, MAX_TEMPO))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn average_and_outliers() {
+        let mut tap_tempo = TapTempo::new(4);
+        assert_eq!(tap_tempo.tap(0), None);
+        assert_eq!(tap_tempo.tap(500_000), Some(120.));
+        // a late tap is left out once the others agree on the tempo
+        tap_tempo.tap(1_000_000);
+        tap_tempo.tap(1_700_000);
+        let tempo = tap_tempo.tap(2_200_000).unwrap();
+        assert_eq!(tempo, 120.);
+
+        // only the last four intervals count
+        for tap in 1..=4 {
+            tap_tempo.tap(2_200_000 + tap * 600_000);
+        }
+        assert_eq!(tap_tempo.tap(2_200_000 + 5 * 600_000), Some(100.));
+
+        // a long pause starts over
+        assert_eq!(tap_tempo.tap(10_000_000), None);
+    }
+}
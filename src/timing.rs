@@ -0,0 +1,115 @@
+/// Tempo used by sources that haven't measured one yet.
+pub const DEFAULT_TEMPO: f64 = 120.;
+
+/// Where the sequencer takes its timeline from, e.g. a Link session or an external clock.
+/// Times are host times in microseconds.
+pub trait TimingSource {
+    /// Called at the start of every buffer, so that the source picks up changes made on other
+    /// threads.
+    fn capture(&mut self) {}
+
+    /// Tempo in beats per minute
+    fn tempo(&self) -> f64;
+
+    /// The beat position at `time`. Sources that align their timeline to bars place bar lines
+    /// on multiples of `quantum`.
+    fn beat_at_time(&self, time: i64, quantum: f64) -> f64;
+
+    /// The position within the bar at `time`, from zero up to `quantum`.
+    fn phase_at_time(&self, time: i64, quantum: f64) -> f64 {
+        self.beat_at_time(time, quantum).rem_euclid(quantum)
+    }
+
+    fn is_playing(&self, time: i64) -> bool;
+}
+
+/// A free running clock for when there is no Link session and no external clock.
+pub struct InternalClock {
+    tempo: f64,
+    playing: bool,
+    /// A point on the timeline, as a time and the beat at that time
+    origin: (i64, f64),
+}
+
+impl InternalClock {
+    pub fn new(tempo: f64) -> Self {
+        Self {
+            tempo,
+            playing: false,
+            origin: (0, 0.),
+        }
+    }
+
+    /// Changes the tempo at `time`, without moving the beat position at that time.
+    pub fn set_tempo(&mut self, tempo: f64, time: i64) {
+        self.origin = (time, self.beat_at_time(time, 1.));
+        self.tempo = tempo;
+    }
+
+    /// Starts playback from the first beat at `time`, or stops it.
+    pub fn set_playing(&mut self, playing: bool, time: i64) {
+        if playing && !self.playing {
+            self.origin = (time, 0.);
+        }
+        self.playing = playing;
+    }
+}
+
+impl TimingSource for InternalClock {
+    fn tempo(&self) -> f64 {
+        self.tempo
+    }
+
+    fn beat_at_time(&self, time: i64, _quantum: f64) -> f64 {
+        let (origin_time, origin_beat) = self.origin;
+        origin_beat + (time - origin_time) as f64 * self.tempo / 60.0e6
+    }
+
+    fn is_playing(&self, _time: i64) -> bool {
+        self.playing
+    }
+}
+
+/// A source that plays at a fixed tempo from beat `beat` at time zero, for tests.
+#[cfg(test)]
+pub struct FakeTiming {
+    pub tempo: f64,
+    pub beat: f64,
+    pub playing: bool,
+}
+
+#[cfg(test)]
+impl TimingSource for FakeTiming {
+    fn tempo(&self) -> f64 {
+        self.tempo
+    }
+
+    fn beat_at_time(&self, time: i64, _quantum: f64) -> f64 {
+        self.beat + time as f64 * self.tempo / 60.0e6
+    }
+
+    fn is_playing(&self, _time: i64) -> bool {
+        self.playing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn internal_clock() {
+        let mut clock = InternalClock::new(120.);
+        assert!(!clock.is_playing(0));
+        clock.set_playing(true, 1_000_000);
+        assert_eq!(clock.beat_at_time(1_000_000, 4.), 0.);
+        assert_eq!(clock.beat_at_time(3_000_000, 4.), 4.);
+
+        // the tempo changes without a jump in the beat position
+        clock.set_tempo(60., 3_000_000);
+        assert_eq!(clock.beat_at_time(3_000_000, 4.), 4.);
+        assert_eq!(clock.beat_at_time(5_500_000, 4.), 6.5);
+        assert_eq!(clock.phase_at_time(5_500_000, 4.), 2.5);
+        assert_eq!(clock.tempo(), 60.);
+    }
+}
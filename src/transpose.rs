@@ -0,0 +1,37 @@
+use wmidi::Note;
+
+/// What happens to notes that are transposed out of the MIDI note range.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutOfRange {
+    /// Play the lowest or highest MIDI note instead
+    Clamp,
+    /// Don't play the note
+    Drop,
+}
+
+/// Shifts `note` by `semitones`.
+pub fn transpose(note: Note, semitones: i32, policy: OutOfRange) -> Option<Note> {
+    let pitch = u8::from(note) as i32 + semitones;
+    match policy {
+        OutOfRange::Clamp => Some(Note::from_u8_lossy(pitch.clamp(0, 127) as u8)),
+        OutOfRange::Drop if (0..=127).contains(&pitch) => Some(Note::from_u8_lossy(pitch as u8)),
+        OutOfRange::Drop => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn out_of_range_policies() {
+        assert_eq!(transpose(Note::C4, 7, OutOfRange::Drop), Some(Note::G4));
+        assert_eq!(transpose(Note::C4, -12, OutOfRange::Clamp), Some(Note::C3));
+        assert_eq!(transpose(Note::G9, 1, OutOfRange::Drop), None);
+        assert_eq!(transpose(Note::G9, 1, OutOfRange::Clamp), Some(Note::G9));
+        assert_eq!(
+            transpose(Note::CMinus1, -24, OutOfRange::Clamp),
+            Some(Note::CMinus1)
+        );
+    }
+}